@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use factoria::dirdat;
+use factoria::command::extract;
+
+fuzz_target!(|input: (&[u8], &[u8], bool)| {
+	let (dir, dat, raw) = input;
+	let Ok(dir) = dirdat::read_dir(dir) else { return };
+	for e in &dir {
+		let _ = extract::read_entry(e, dat, raw);
+	}
+});
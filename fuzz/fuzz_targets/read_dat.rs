@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use factoria::dirdat;
+
+fuzz_target!(|data: &[u8]| {
+	let _ = dirdat::read_dat(data);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use factoria::dirdat;
+
+fuzz_target!(|data: &[u8]| {
+	if let Ok(dir) = dirdat::read_dir(data) {
+		let written = dirdat::write_dir(&dir).unwrap();
+		assert_eq!(dirdat::read_dir(&written).unwrap(), dir);
+	}
+});
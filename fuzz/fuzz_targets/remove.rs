@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use factoria::dirdat;
+use factoria::command::remove;
+
+fuzz_target!(|input: (&[u8], &[u8], bool)| {
+	let (dir, dat, force) = input;
+	let Ok(mut dir) = dirdat::read_dir(dir) else { return };
+	let mut dat = dat.to_vec();
+	let len = dat.len();
+	let names = dir.iter().map(|e| e.name).collect::<Vec<_>>();
+	for name in names {
+		let _ = remove::remove_entry(&mut dir, &mut dat, name, force);
+	}
+	assert_eq!(dat.len(), len);
+	assert_eq!(dirdat::read_dir(&dirdat::write_dir(&dir).unwrap()).unwrap(), dir);
+});
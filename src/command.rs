@@ -5,6 +5,11 @@ pub mod remove;
 pub mod rebuild;
 pub mod index;
 pub mod create;
+pub mod recompress;
+pub mod stats;
+pub mod map;
+pub mod defrag;
+pub mod set;
 
 #[derive(Debug, Clone, clap::Parser)]
 #[command(args_conflicts_with_subcommands = true, disable_help_subcommand = true)]
@@ -33,6 +38,16 @@ pub enum Command {
 	Index(index::Command),
 	/// Create an brand new archive from scratch a json index file
 	Create(create::Command),
+	/// Change the compression of files in archives
+	Recompress(recompress::Command),
+	/// Show a summary of archives' contents
+	Stats(stats::Command),
+	/// Draw a map of the space used in archives
+	Map(map::Command),
+	/// Clear out unused data from archives without making a copy
+	Defrag(defrag::Command),
+	/// Change the metadata of files in archives
+	Set(set::Command),
 }
 
 pub fn run(cli: Cli) -> eyre::Result<()> {
@@ -45,5 +60,10 @@ pub fn run(cli: Cli) -> eyre::Result<()> {
 		Command::Rebuild(cmd) => rebuild::run(&cmd),
 		Command::Index(cmd) => index::run(&cmd),
 		Command::Create(cmd) => create::run(&cmd),
+		Command::Recompress(cmd) => recompress::run(&cmd),
+		Command::Stats(cmd) => stats::run(&cmd),
+		Command::Map(cmd) => map::run(&cmd),
+		Command::Defrag(cmd) => defrag::run(&cmd),
+		Command::Set(cmd) => set::run(&cmd),
 	}
 }
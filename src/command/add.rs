@@ -1,13 +1,14 @@
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{prelude::*, SeekFrom};
 use std::time::SystemTime;
 
-use camino::{Utf8PathBuf, Utf8Path};
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::ValueHint;
-use clap::builder::TypedValueParser;
 
 use eyre_span::emit;
 use falcompress::bzip;
+use crate::compression::Compression;
 use crate::dirdat::{self, DirEntry, Name};
 
 #[derive(Debug, Clone, clap::Args)]
@@ -17,35 +18,107 @@ use crate::dirdat::{self, DirEntry, Name};
 /// If the file to be added already exists in the archive, it will be updated.
 /// This may require expanding the dat file, leaving a gap where the previous data was.
 /// To eliminate this gap, use `factorial rebuild`.
+///
+/// Files are stored under their own file name, unless given as `SRC=NAME`, or with `--as`.
+/// A source of `-` reads the data from stdin; this needs a name to be given.
 pub struct Command {
 	/// Compress newly-added files (updated files keep existing compression)
-	#[clap(
-		short='c', long,
-		require_equals = true, num_args=0..=1, default_missing_value="2",
-		value_parser = clap::builder::PossibleValuesParser::new(["1", "2"])
-			.map(|a| match a.as_str() {
-				"1" => bzip::CompressMode::Mode1,
-				"2" => bzip::CompressMode::Mode2,
-				_ => unreachable!()
-			})
-	)]
-	compression: Option<bzip::CompressMode>,
+	#[clap(short='c', long, value_enum, require_equals = true, num_args=0..=1, default_missing_value="2")]
+	compression: Option<Compression>,
+
+	/// With -c=auto, only compress files if it makes them at least this many percent smaller
+	#[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+	min_savings: u8,
+
+	/// Check that compressed data decompresses correctly before writing it
+	#[clap(long)]
+	verify: bool,
 
 	/// Reserve space in the data for later updates
 	#[clap(short, long)]
 	reserve: Option<usize>,
 
+	/// Name to store the file as in the archive, if only one file is given
+	#[clap(long = "as")]
+	as_name: Option<String>,
+
+	/// Index to place the file at, if only one file is given, as decimal or 0x-prefixed hex
+	///
+	/// The slot must be empty, soft-deleted, or already hold the file.
+	#[clap(long, value_parser = parse_id)]
+	id: Option<usize>,
+
+	/// Modify the archive even if the .dat does not seem to belong with the .dir
+	#[clap(long)]
+	allow_mismatch: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
 	/// .dir file to insert into
 	#[clap(value_hint = ValueHint::FilePath, required = true)]
 	dir_file: Utf8PathBuf,
 
-	/// Files to insert
-	#[clap(value_hint = ValueHint::FilePath, required = true)]
-	file: Vec<Utf8PathBuf>,
+	/// Files to insert, as PATH, PATH=NAME, or -=NAME for stdin
+	#[clap(value_hint = ValueHint::FilePath, required = true, value_parser = parse_source)]
+	file: Vec<Source>,
+}
+
+/// Where to read a file to add from, and what to call it.
+#[derive(Debug, Clone)]
+struct Source {
+	/// `None` for stdin.
+	path: Option<Utf8PathBuf>,
+	name: Option<String>,
+}
+
+impl std::fmt::Display for Source {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match &self.path {
+			Some(path) => write!(f, "{path}")?,
+			None => f.write_str("-")?,
+		}
+		if let Some(name) = &self.name {
+			write!(f, "={name}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Splits `PATH=NAME`. An existing file is always taken as a plain path, and otherwise only an `=`
+/// in the last component counts, so that directories with `=` in their names still work.
+fn parse_source(s: &str) -> Result<Source, String> {
+	let file_name = s.rfind(std::path::is_separator).map_or(0, |i| i + 1);
+	let (path, name) = match s[file_name..].rfind('=') {
+		Some(i) if !Utf8Path::new(s).exists() => (&s[..file_name + i], Some(s[file_name + i + 1..].to_owned())),
+		_ => (s, None),
+	};
+	Ok(Source {
+		path: (path != "-").then(|| path.into()),
+		name,
+	})
 }
 
 #[tracing::instrument(skip_all, fields(path=%cmd.dir_file))]
 pub fn run(cmd: &Command) -> eyre::Result<()> {
+	let mut sources = cmd.file.clone();
+	if let Some(name) = &cmd.as_name {
+		eyre::ensure!(sources.len() == 1, "--as can only be used with a single file");
+		eyre::ensure!(sources[0].name.is_none(), "cannot use both --as and {}", sources[0]);
+		sources[0].name = Some(name.clone());
+	}
+	eyre::ensure!(cmd.id.is_none() || sources.len() == 1, "--id can only be used with a single file");
+	eyre::ensure!(
+		sources.iter().filter(|s| s.path.is_none()).count() <= 1,
+		"stdin can only be read once",
+	);
+
+	let _lock = crate::util::lock(&cmd.dir_file, true, cmd.wait)?;
 	let mut dir = dirdat::read_dir(&std::fs::read(&cmd.dir_file)?)?;
 
 	let mut dat = File::options()
@@ -56,59 +129,171 @@ pub fn run(cmd: &Command) -> eyre::Result<()> {
 		.create(false)
 		.open(&cmd.dir_file.with_extension("dat"))?;
 
-	dat.seek(SeekFrom::Start(0))?;
-	eyre::ensure!(dat.read_array()? == *b"LB DAT\x1A\0", "invalid dat file");
+	let table = crate::util::read_dat_table(&mut dat)?;
+	crate::util::check_pair(&cmd.dir_file, &dir, &table, dat.metadata()?.len() as usize, !cmd.allow_mismatch)?;
 
-	for file in &cmd.file {
-		emit(add(cmd, &mut dir, &mut dat, file));
+	// Everything that touches the dir and dat is done in order, only reading and compression are parallel
+	let mut names = HashSet::new();
+	let mut jobs = Vec::new();
+	for source in sources {
+		if let Some(job) = emit(prepare(cmd, &mut dir, &mut dat, &mut names, source)) {
+			jobs.push(job);
+		}
 	}
 
-	std::fs::write(&cmd.dir_file, dirdat::write_dir(&dir))?;
+	let min_savings = cmd.min_savings;
+	let verify = cmd.verify;
+	let iter = crate::util::par_map(jobs.into_iter(), move |job| {
+		let data = read_source(&job.source)
+			.and_then(|data| job.compression.compress(data, min_savings, verify));
+		(job, data)
+	});
+	for (job, data) in iter {
+		emit(write(cmd, &mut dir, &mut dat, job, data));
+	}
+
+	emit(trim(&mut dat, &mut dir));
+	write_table(&mut dat, &dir)?;
+
+	std::fs::write(&cmd.dir_file, dirdat::write_dir(&dir)?)?;
 
 	Ok(())
 }
 
-#[tracing::instrument(skip_all, fields(file=%file))]
-fn add(cmd: &Command, dir: &mut [DirEntry], dat: &mut File, file: &Utf8Path) -> eyre::Result<()> {
-	// Starting with a stat call gives us a nice error if it doesn't exist
-	let timestamp = std::fs::metadata(file)?
-		.modified()
-		.unwrap_or_else(|_| SystemTime::now());
+/// A file that has been assigned a slot, waiting to be read, compressed, and written.
+struct Job {
+	source: Source,
+	id: usize,
+	exists: bool,
+	timestamp: u32,
+	compression: Compression,
+	/// The slot as it was before, to put back if the file cannot be added after all.
+	previous: DirEntry,
+}
+
+#[tracing::instrument(skip_all, fields(file=%source))]
+fn prepare(
+	cmd: &Command,
+	dir: &mut [DirEntry],
+	dat: &mut File,
+	names: &mut HashSet<Name>,
+	source: Source,
+) -> eyre::Result<Job> {
+	let timestamp = source_timestamp(&source)?;
 	let timestamp = timestamp.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
 
-	let name = Name::try_from(file.file_name().unwrap())?;
+	let name = match (&source.name, &source.path) {
+		(Some(name), _) => name.as_str(),
+		(None, Some(path)) => path.file_name().ok_or_else(|| eyre::eyre!("path has no file name"))?,
+		(None, None) => eyre::bail!("a name is needed to add from stdin; use -=NAME or --as"),
+	};
+	let name = Name::encode(name, cmd.encoding)?;
+	let timestamp = dirdat::to_u32(name, "timestamp", timestamp)?;
+	eyre::ensure!(names.insert(name), "{name} is given more than once");
 
-	let id = get_id(dir, name)?;
+	let id = match cmd.id {
+		Some(id) => claim_id(dir, name, id)?,
+		None => get_id(dir, name)?,
+	};
+	let previous = dir[id].clone();
 	let ent = &mut dir[id];
+	ent.name = name;
 
 	let exists = ent.timestamp != 0;
 
 	let compression = if exists {
-		dat.seek(SeekFrom::Start(16 + 4 * id as u64))?;
-		let dat_offset = u32::from_le_bytes(dat.read_array()?) as usize;
-		eyre::ensure!(dat_offset == ent.offset, "mismatched dat file offset");
-		dat.seek(SeekFrom::Start(ent.offset as u64))?;
-		let mut existing = vec![0; ent.size];
-		dat.read_exact(&mut existing)?;
-		bzip::compression_info_ed6(&existing).map(|a| a.1.unwrap_or_default())
+		let existing = read_existing(dat, ent, id)?;
+		Compression::from(bzip::compression_info_ed6(&existing).map(|a| a.1.unwrap_or_default()))
 	} else {
-		cmd.compression
+		cmd.compression.unwrap_or_default()
 	};
 
 	match compression {
-		Some(bzip::CompressMode::Mode1) => tracing::debug!("using compression mode 1"),
-		Some(bzip::CompressMode::Mode2) => tracing::debug!("using compression mode 2"),
-		None => tracing::debug!("using no compression"),
+		Compression::Mode1 => tracing::debug!("using compression mode 1"),
+		Compression::Mode2 => tracing::debug!("using compression mode 2"),
+		Compression::None => tracing::debug!("using no compression"),
+		Compression::Auto => tracing::debug!("choosing compression automatically"),
 	}
 
-	let data = std::fs::read(file)?;
-	let mut data = match compression {
-		Some(method) => bzip::compress_ed6_to_vec(&data, method),
-		None => data,
+	Ok(Job { source, id, exists, timestamp, compression, previous })
+}
+
+#[tracing::instrument(skip_all, fields(file=%job.source))]
+fn write(
+	cmd: &Command,
+	dir: &mut [DirEntry],
+	dat: &mut File,
+	job: Job,
+	data: eyre::Result<Vec<u8>>,
+) -> eyre::Result<()> {
+	let id = job.id;
+	let ent = &mut dir[id];
+	let data = match data {
+		Ok(data) => data,
+		Err(e) => {
+			*ent = job.previous;
+			return Err(e)
+		}
 	};
+	store(dat, ent, id, job.exists, data, cmd.reserve)?;
+	ent.timestamp = job.timestamp;
+
+	tracing::info!("added {} as {:04X}", ent.name, id);
+
+	Ok(())
+}
+
+/// Gets the modification time of the data to add.
+///
+/// This is a stat call, so that a missing file is noticed before it is given a slot.
+fn source_timestamp(source: &Source) -> eyre::Result<SystemTime> {
+	match &source.path {
+		Some(path) => Ok(std::fs::metadata(path)?
+			.modified()
+			.unwrap_or_else(|_| SystemTime::now())),
+		None => Ok(SystemTime::now()),
+	}
+}
+
+/// Reads the data to add.
+fn read_source(source: &Source) -> eyre::Result<Vec<u8>> {
+	match &source.path {
+		Some(path) => Ok(std::fs::read(path)?),
+		None => {
+			let mut data = Vec::new();
+			std::io::stdin().read_to_end(&mut data)?;
+			Ok(data)
+		}
+	}
+}
+
+/// Reads the stored data of an existing entry, after checking that the dat agrees about where it is.
+pub(crate) fn read_existing(dat: &mut File, ent: &DirEntry, id: usize) -> eyre::Result<Vec<u8>> {
+	let dat_len = dat.seek(SeekFrom::End(0))? as usize;
+	ent.alloc_range(dat_len)?;
+	dat.seek(SeekFrom::Start(16 + 4 * id as u64))?;
+	let dat_offset = u32::from_le_bytes(dat.read_array()?) as usize;
+	eyre::ensure!(dat_offset == ent.offset, "mismatched dat file offset");
+	dat.seek(SeekFrom::Start(ent.offset as u64))?;
+	let mut existing = vec![0; ent.size];
+	dat.read_exact(&mut existing)?;
+	Ok(existing)
+}
+
+/// Writes already compressed data for an entry, padded to `reserve`.
+///
+/// The data is written in place if it fits in the existing allocation, and appended to the dat otherwise.
+pub(crate) fn store(
+	dat: &mut File,
+	ent: &mut DirEntry,
+	id: usize,
+	exists: bool,
+	mut data: Vec<u8>,
+	reserve: Option<usize>,
+) -> eyre::Result<()> {
 	let size = data.len();
 
-	while data.len() < cmd.reserve.unwrap_or(0) {
+	while data.len() < reserve.unwrap_or(0) {
 		data.push(0);
 	}
 
@@ -125,35 +310,83 @@ fn add(cmd: &Command, dir: &mut [DirEntry], dat: &mut File, file: &Utf8Path) ->
 
 	if needs_alloc {
 		let pos = dat.seek(SeekFrom::End(0))?;
+		dirdat::to_u32(ent.name, "end offset", pos + data.len() as u64)?;
+		dirdat::to_u32(ent.name, "reserved size", reserve.unwrap_or(data.len()) as u64)?;
+		let pos32 = dirdat::to_u32(ent.name, "offset", pos)?;
 		dat.write_all(&data)?;
 		dat.seek(SeekFrom::Start(16 + 4 * id as u64))?;
-		dat.write_all(&u32::to_le_bytes(pos as u32))?;
+		dat.write_all(&u32::to_le_bytes(pos32))?;
 		if exists {
 			dat.seek(SeekFrom::Start(ent.offset as u64))?;
 			dat.write_all(&vec![0; ent.reserved_size.max(ent.size)])?;
 		}
 		ent.offset = pos as usize;
-		ent.reserved_size = cmd.reserve.unwrap_or(data.len());
+		ent.reserved_size = reserve.unwrap_or(data.len());
 	} else {
 		dat.seek(SeekFrom::Start(ent.offset as u64))?;
 		dat.write_all(&data)?;
 	}
 
 	ent.size = size;
-	ent.timestamp = timestamp as u32;
 
-	tracing::info!("added {} as {:04X}", ent.name, id);
+	Ok(())
+}
+
+/// Truncates zeroed space at the end of the dat that no file is using.
+///
+/// Space that is not zeroed is left for `factoria rebuild` to deal with, in case it is something other than garbage.
+/// Files without any data that pointed into the truncated space are moved to the new end; use [`write_table`]
+/// afterwards to update the offset table to match.
+pub(crate) fn trim(dat: &mut File, dir: &mut [DirEntry]) -> eyre::Result<()> {
+	let dat_len = dat.seek(SeekFrom::End(0))?;
+	let mut end = 16 + 4 * (dir.len() as u64 + 1);
+	eyre::ensure!(dat_len >= end, "dat offset table is too short");
+	for e in dir.iter() {
+		if e.name != Name::default() && e.size.max(e.reserved_size) != 0 {
+			end = end.max(e.alloc_range(dat_len as usize)?.end as u64);
+		}
+	}
+
+	let mut len = dat_len;
+	let mut buf = vec![0; 1 << 16];
+	while len > end {
+		let n = (len - end).min(buf.len() as u64) as usize;
+		dat.seek(SeekFrom::Start(len - n as u64))?;
+		dat.read_exact(&mut buf[..n])?;
+		if let Some(i) = buf[..n].iter().rposition(|&b| b != 0) {
+			len -= (n - i - 1) as u64;
+			break
+		}
+		len -= n as u64;
+	}
 
+	for e in dir.iter_mut() {
+		if e.name != Name::default() && e.offset as u64 > len {
+			e.offset = len as usize;
+		}
+	}
+	if len < dat_len {
+		dat.set_len(len)?;
+		tracing::info!("truncated {} bytes of free space", dat_len - len);
+	}
+	Ok(())
+}
+
+/// Rewrites the whole offset table in the dat to match the dir, in the same way as `factoria rebuild`.
+pub(crate) fn write_table(dat: &mut File, dir: &[DirEntry]) -> eyre::Result<()> {
+	let dat_len = dat.seek(SeekFrom::End(0))?;
+	eyre::ensure!(dat_len >= 16 + 4 * (dir.len() as u64 + 1), "dat offset table is too short");
+	dat.seek(SeekFrom::Start(16))?;
+	dat.write_all(&dirdat::write_dat_table(dir)?)?;
 	Ok(())
 }
 
 #[tracing::instrument(skip_all)]
-fn get_id(dir: &mut [DirEntry], name: Name) -> eyre::Result<usize> {
+fn get_id(dir: &[DirEntry], name: Name) -> eyre::Result<usize> {
 	if let Some(id) = dir.iter().position(|e| e.name == name) {
 		tracing::debug!("found existing at {id:04X}");
 		Ok(id)
 	} else if let Some(id) = dir.iter().position(|e| e.name == Name::default()) {
-		dir[id].name = name;
 		tracing::debug!("found empty at {id:04X}");
 		Ok(id)
 	} else {
@@ -161,7 +394,35 @@ fn get_id(dir: &mut [DirEntry], name: Name) -> eyre::Result<usize> {
 	}
 }
 
-trait ReadArray: Read {
+/// Like `get_id`, but for a specific slot.
+#[tracing::instrument(skip_all, fields(id=%format_args!("{id:04X}")))]
+fn claim_id(dir: &[DirEntry], name: Name, id: usize) -> eyre::Result<usize> {
+	eyre::ensure!(id < dir.len(), "index has only {} slots; use `factoria rebuild` to allocate more", dir.len());
+	if let Some(other) = dir.iter().position(|e| e.name == name) {
+		eyre::ensure!(other == id, "already in archive at {other:04X}");
+	}
+
+	let ent = &dir[id];
+	if ent.name == name {
+		tracing::debug!("found existing");
+	} else if ent.name == Name::default() {
+		tracing::debug!("found empty");
+	} else if ent.timestamp == 0 {
+		tracing::warn!("reusing slot of deleted file {}", ent.name);
+	} else {
+		eyre::bail!("slot is occupied by {}", ent.name);
+	}
+	Ok(id)
+}
+
+fn parse_id(s: &str) -> Result<usize, String> {
+	match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+		Some(hex) => usize::from_str_radix(hex, 16),
+		None => s.parse(),
+	}.map_err(|e| e.to_string())
+}
+
+pub(crate) trait ReadArray: Read {
 	fn read_array<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
 		let mut buf = [0; N];
 		self.read_exact(&mut buf)?;
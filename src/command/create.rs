@@ -1,6 +1,7 @@
-use std::collections::BTreeMap;
-use std::io::{prelude::*, SeekFrom};
-use std::time::SystemTime;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{prelude::*, BufReader, SeekFrom};
+use std::time::{Duration, SystemTime};
 
 use camino::{Utf8PathBuf, Utf8Path};
 use clap::ValueHint;
@@ -8,23 +9,77 @@ use indicatif::ProgressIterator;
 use serde::de::{self, Deserialize};
 use eyre_span::emit;
 
-use falcompress::bzip;
+use crate::compression::Compression;
 use crate::dirdat::{self, DirEntry, Name};
+use crate::packing::{Packing, SortKey};
+use super::extract::Layout;
 
 #[derive(Debug, Clone, clap::Args)]
 #[command(arg_required_else_help = true)]
+#[clap(group = clap::ArgGroup::new("source").required(true))]
 pub struct Command {
 	/// Location of the resulting .dir file. .dat is placed next to it.
 	#[clap(long, short, value_hint = ValueHint::DirPath)]
 	output: Option<Utf8PathBuf>,
 
+	/// If the archive is in use by another process, wait for it instead of failing
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
+	/// Layout the files were extracted with, for entries that do not specify a name
+	#[clap(long, value_enum, default_value = "flat")]
+	layout: Layout,
+
+	#[clap(flatten)]
+	packing: Packing,
+
+	/// Create the archive from the files in a directory, rather than from an index
+	#[clap(long, value_hint = ValueHint::DirPath, group = "source")]
+	from_dir: Option<Utf8PathBuf>,
+	/// Create the archive from the files in a .tar file, rather than from an index
+	#[clap(long, value_hint = ValueHint::FilePath, group = "source")]
+	from_tar: Option<Utf8PathBuf>,
+	/// Create the archive from the files in a .zip file, rather than from an index
+	#[clap(long, value_hint = ValueHint::FilePath, group = "source")]
+	from_zip: Option<Utf8PathBuf>,
+	/// Settings for files matching a glob when not using an index, as GLOB:compress=1,reserve=4096
+	///
+	/// compress can be 1, 2, none, or auto. If several rules match a file, later ones take precedence.
+	/// A reserve smaller than the file is ignored.
+	#[clap(long, value_parser = parse_rule, conflicts_with = "json_file")]
+	rule: Vec<Rule>,
+
+	/// For files compressed with `auto`, only compress them if it makes them at least this many percent smaller
+	#[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+	min_savings: u8,
+
+	/// Check that compressed data decompresses correctly before writing it
+	#[clap(long)]
+	verify: bool,
+
 	/// The .json indexes to reconstruct
-	#[clap(value_hint = ValueHint::FilePath, required = true)]
+	#[clap(value_hint = ValueHint::FilePath, group = "source")]
 	json_file: Vec<Utf8PathBuf>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct FileId(u16);
+/// A `--rule` option.
+#[derive(Debug, Clone)]
+struct Rule {
+	glob: globset::GlobMatcher,
+	compress: Option<Compression>,
+	reserve: Option<usize>,
+}
+
+/// A key in the index: four hex digits, or eight if prefixed with the archive number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct FileId {
+	archive: Option<u16>,
+	id: u16,
+}
 
 #[derive(Debug, Clone, serde::Deserialize)]
 #[serde(remote = "Entry")]
@@ -32,15 +87,30 @@ struct Entry {
 	path: Option<Utf8PathBuf>,
 	name: Option<String>,
 	#[serde(default, deserialize_with="parse_compress_mode")]
-	compress: Option<bzip::CompressMode>,
+	compress: Compression,
 	reserve: Option<usize>,
+	/// Reserved size from a `--rule`, which unlike `reserve` is never less than the file's size.
+	#[serde(skip)]
+	min_reserve: Option<usize>,
 	#[serde(default)]
 	unknown1: u32,
 	#[serde(default)]
 	unknown2: usize,
+	/// Contents and modification time of files that do not come from the file system.
+	#[serde(skip)]
+	data: Option<(Vec<u8>, SystemTime)>,
 }
 
 pub fn run(cmd: &Command) -> eyre::Result<()> {
+	if let Some(dir) = &cmd.from_dir {
+		return from_dir(cmd, dir)
+	}
+	if let Some(tar) = &cmd.from_tar {
+		return from_tar(cmd, tar)
+	}
+	if let Some(zip) = &cmd.from_zip {
+		return from_zip(cmd, zip)
+	}
 	for json_file in &cmd.json_file {
 		emit(create(cmd, json_file));
 	}
@@ -54,15 +124,163 @@ fn create(cmd: &Command, json_file: &Utf8Path) -> eyre::Result<()> {
 
 	let out_dir = crate::util::output(cmd.output.as_deref(), json_file, "dir", cmd.json_file.len())?;
 
-	tracing::Span::current().record("out", tracing::field::display(&out_dir));
-	std::fs::create_dir_all(out_dir.parent().unwrap())?;
+	let archives = json.keys().map(|k| k.archive).collect::<BTreeSet<_>>();
+	eyre::ensure!(archives.len() <= 1, "index mixes file ids from different archives");
 
-	let size = json.last_key_value().map(|a| a.0.0 + 1).unwrap_or_default() as usize;
+	let size = json.keys().map(|k| k.id as usize + 1).max().unwrap_or_default();
 	let mut entries = vec![None; size];
 	for (k, v) in json {
-		entries[k.0 as usize] = v
+		entries[k.id as usize] = v
+	}
+
+	write_archive(cmd, &out_dir, entries, json_file.parent().unwrap())
+}
+
+#[tracing::instrument(skip_all, fields(path=%dir, out))]
+fn from_dir(cmd: &Command, dir: &Utf8Path) -> eyre::Result<()> {
+	fn walk(base: &Utf8Path, path: &Utf8Path, out: &mut Vec<(Utf8PathBuf, Option<(Vec<u8>, SystemTime)>)>) -> eyre::Result<()> {
+		for ent in path.read_dir_utf8()? {
+			let ent = ent?;
+			if ent.file_type()?.is_dir() {
+				walk(base, ent.path(), out)?;
+			} else if path == base && super::extract::is_manifest(ent.file_name()) {
+				tracing::debug!("skipping {}", ent.path());
+			} else {
+				out.push((ent.path().strip_prefix(base)?.to_owned(), None));
+			}
+		}
+		Ok(())
 	}
 
+	let mut files = Vec::new();
+	walk(dir, dir, &mut files)?;
+	let entries = from_files(cmd, files)?;
+	let out_dir = crate::util::output(cmd.output.as_deref(), dir, "dir", 1)?;
+	write_archive(cmd, &out_dir, entries, dir)
+}
+
+#[tracing::instrument(skip_all, fields(path=%path, out))]
+fn from_tar(cmd: &Command, path: &Utf8Path) -> eyre::Result<()> {
+	let mut tar = tar::Archive::new(BufReader::new(std::fs::File::open(path)?));
+	let mut files = Vec::new();
+	for entry in tar.entries()? {
+		let mut entry = entry?;
+		if !entry.header().entry_type().is_file() {
+			continue
+		}
+		let name = Utf8PathBuf::try_from(entry.path()?.into_owned())?;
+		let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+		let mut data = Vec::with_capacity(entry.size() as usize);
+		entry.read_to_end(&mut data)?;
+		files.push((name, Some((data, mtime))));
+	}
+
+	let entries = from_files(cmd, files)?;
+	let out_dir = crate::util::output(cmd.output.as_deref(), path, "dir", 1)?;
+	write_archive(cmd, &out_dir, entries, path)
+}
+
+#[tracing::instrument(skip_all, fields(path=%path, out))]
+fn from_zip(cmd: &Command, path: &Utf8Path) -> eyre::Result<()> {
+	let mut zip = zip::ZipArchive::new(BufReader::new(std::fs::File::open(path)?))?;
+	let mut files = Vec::new();
+	for i in 0..zip.len() {
+		let mut file = zip.by_index(i)?;
+		if file.is_dir() {
+			continue
+		}
+		let name = file.enclosed_name()
+			.ok_or_else(|| eyre::eyre!("{} is not a valid path", file.name()))?
+			.to_owned();
+		let name = Utf8PathBuf::try_from(name)?;
+		let t = file.last_modified();
+		let mtime = chrono::NaiveDate::from_ymd_opt(t.year() as i32, t.month() as u32, t.day() as u32)
+			.and_then(|d| d.and_hms_opt(t.hour() as u32, t.minute() as u32, t.second() as u32))
+			.map_or(SystemTime::UNIX_EPOCH, |t| SystemTime::UNIX_EPOCH + Duration::from_secs(t.timestamp().max(0) as u64));
+		let mut data = Vec::with_capacity(file.size() as usize);
+		file.read_to_end(&mut data)?;
+		files.push((name, Some((data, mtime))));
+	}
+
+	let entries = from_files(cmd, files)?;
+	let out_dir = crate::util::output(cmd.output.as_deref(), path, "dir", 1)?;
+	write_archive(cmd, &out_dir, entries, path)
+}
+
+/// Turns a file tree into index entries, with ids assigned in path order.
+///
+/// With `--layout id`, files are instead placed at the id in their name, and any gaps are filled
+/// with placeholders. Files without an id go after the highest one.
+fn from_files(cmd: &Command, mut files: Vec<(Utf8PathBuf, Option<(Vec<u8>, SystemTime)>)>) -> eyre::Result<Vec<Option<Entry>>> {
+	files.sort_by(|a, b| a.0.cmp(&b.0));
+
+	let mut names = HashMap::new();
+	let mut failed = false;
+	for (path, _) in &files {
+		let file_name = path.file_name().unwrap_or_default();
+		match Name::encode(&cmd.layout.name(file_name), cmd.encoding) {
+			// Files with different ids may share a name, as they do in some archives
+			Ok(name) => if let Some(prev) = names.insert((cmd.layout.id(file_name), name), path) {
+				tracing::error!("{prev} and {path} would both be named {name}");
+				failed = true;
+			}
+			Err(e) => {
+				tracing::error!("{path}: {e}");
+				failed = true;
+			}
+		}
+	}
+	eyre::ensure!(!failed, "some files cannot be added to the archive");
+
+	let ids = files.iter()
+		.map(|(path, _)| cmd.layout.id(path.file_name().unwrap_or_default()))
+		.collect::<Vec<_>>();
+	let mut next = ids.iter().flatten().max().map_or(0, |id| id + 1);
+	let ids = ids.into_iter().map(|id| id.unwrap_or_else(|| {
+		next += 1;
+		next - 1
+	})).collect::<Vec<_>>();
+
+	let mut entries = Vec::new();
+	entries.resize_with(next, || None);
+	for ((path, data), id) in files.into_iter().zip(ids) {
+		let mut e = Entry {
+			path: None,
+			name: None,
+			compress: Compression::None,
+			reserve: None,
+			min_reserve: None,
+			unknown1: 0,
+			unknown2: 0,
+			data,
+		};
+		for rule in cmd.rule.iter().filter(|r| r.glob.is_match(path.as_str())) {
+			e.compress = rule.compress.unwrap_or(e.compress);
+			e.min_reserve = rule.reserve.or(e.min_reserve);
+		}
+		if let Some(Entry { path: Some(prev), .. }) = &entries[id] {
+			tracing::error!("{prev} and {path} both have id {id:04X}");
+			failed = true;
+		}
+		e.path = Some(path);
+		entries[id] = Some(e);
+	}
+	eyre::ensure!(!failed, "some files cannot be added to the archive");
+
+	Ok(entries)
+}
+
+fn write_archive(
+	cmd: &Command,
+	out_dir: &Utf8Path,
+	entries: Vec<Option<Entry>>,
+	base: &Utf8Path,
+) -> eyre::Result<()> {
+	tracing::Span::current().record("out", tracing::field::display(&out_dir));
+	std::fs::create_dir_all(out_dir.parent().unwrap())?;
+	let _lock = crate::util::lock(out_dir, true, cmd.wait)?;
+	let size = entries.len();
+
 	// TODO lots of duplicated code between here and rebuild
 
 	let mut out_dat = std::fs::File::create(out_dir.with_extension("dat.tmp"))?;
@@ -72,76 +290,116 @@ fn create(cmd: &Command, json_file: &Utf8Path) -> eyre::Result<()> {
 		out_dat.write_all(&u32::to_le_bytes(0))?;
 	}
 
-	let mut dir = Vec::with_capacity(size);
+	let names = entries.iter()
+		.map(|e| e.as_ref().map(|e| entry_name(e, cmd.layout).to_lowercase()))
+		.collect::<Vec<_>>();
+	let mut order = names.iter().enumerate()
+		.filter_map(|(id, name)| Some(SortKey { id, name: name.as_deref()?, offset: None }))
+		.collect::<Vec<_>>();
+	cmd.packing.order(&mut order);
+	let order = order.iter().map(|k| k.id).collect::<Vec<_>>();
+
+	let mut dir = vec![DirEntry::default(); size];
 	let style = indicatif::ProgressStyle::with_template("{bar} {prefix} {pos}/{len}").unwrap()
 		.progress_chars("█🮆🮅🮄▀🮃🮂▔ ");
-	let ind = indicatif::ProgressBar::new(entries.iter().filter(|a| a.is_some()).count() as _)
+	let ind = indicatif::ProgressBar::new(order.len() as _)
 		.with_style(style)
 		.with_prefix(out_dir.to_string());
-	let iter = par_map(
-		entries.into_iter(),
+	let mut entries = entries;
+	let iter = crate::util::par_map(
+		order.into_iter().map(move |id| (id, entries[id].take())),
 		{
-			let json_file = json_file.to_owned();
-			move |e| process_entry(e, &json_file)
+			let base = base.to_owned();
+			let encoding = cmd.encoding;
+			let layout = cmd.layout;
+			let min_savings = cmd.min_savings;
+			let verify = cmd.verify;
+			move |(id, e)| (id, process_entry(e, &base, encoding, layout, min_savings, verify))
 		},
 	).progress_with(ind.clone());
-	for (id, e) in iter.enumerate() {
+	let mut pos = out_dat.seek(SeekFrom::End(0))?;
+	for (id, e) in iter {
 		let (mut ent, data) = e?;
 
-		if let Some(data) = data {
-			let pos = out_dat.seek(SeekFrom::End(0))?;
-			ent.offset = pos as usize;
+		if let Some(mut data) = data {
+			if ent.timestamp != 0 {
+				ent.reserved_size = cmd.packing.reserve(&ent.name.decode(cmd.encoding), ent.size, ent.reserved_size);
+			}
+			data.resize(data.len().max(ent.reserved_size), 0);
+			let start = cmd.packing.align(pos);
+			out_dat.write_all(&vec![0; (start - pos) as usize])?;
 			out_dat.write_all(&data)?;
-			let pos2 = out_dat.seek(SeekFrom::End(0))?;
-			out_dat.seek(SeekFrom::Start(16 + 4 * id as u64))?;
-			out_dat.write_all(&u32::to_le_bytes(pos as u32))?;
-			out_dat.write_all(&u32::to_le_bytes(pos2 as u32))?;
+			ent.offset = start as usize;
+			pos = start + data.len() as u64;
+			dirdat::to_u32(ent.name, "end offset", pos)?;
 		}
-		dir.push(ent)
+		dir[id] = ent;
 	}
 	ind.abandon();
 
+	out_dat.seek(SeekFrom::Start(16))?;
+	out_dat.write_all(&dirdat::write_dat_table(&dir)?)?;
+
+	let dir_data = dirdat::write_dir(&dir)?;
 	std::fs::rename(out_dir.with_extension("dat.tmp"), out_dir.with_extension("dat"))?;
-	std::fs::write(&out_dir, dirdat::write_dir(&dir))?;
+	std::fs::write(out_dir, dir_data)?;
 	
 	tracing::info!("created");
 
 	Ok(())
 }
 
-fn process_entry(e: Option<Entry>, json_file: &Utf8Path) -> eyre::Result<(DirEntry, Option<Vec<u8>>)> {
+fn entry_name(e: &Entry, layout: Layout) -> Cow<'_, str> {
+	match e {
+		Entry { name: Some(name), .. } => Cow::Borrowed(name.as_str()),
+		Entry { path: Some(path), .. } => layout.name(path.file_name().unwrap()),
+		_ => unreachable!()
+	}
+}
+
+/// Reads and compresses an entry's data. Paths are relative to `base`.
+fn process_entry(
+	e: Option<Entry>,
+	base: &Utf8Path,
+	encoding: dirdat::Encoding,
+	layout: Layout,
+	min_savings: u8,
+	verify: bool,
+) -> eyre::Result<(DirEntry, Option<Vec<u8>>)> {
 	let mut ent = DirEntry::default();
 	let data = if let Some(e) = e {
-		let name = match &e {
-			Entry { name: Some(name), .. } => name.as_str(),
-			Entry { path: Some(path), .. } => path.file_name().unwrap(),
-			_ => unreachable!()
-		};
+		let name = entry_name(&e, layout);
 		let _span = tracing::info_span!("file", name=%name, path=tracing::field::Empty).entered();
-		ent.name = Name::try_from(name)?;
+		ent.name = Name::encode(&name, encoding)?;
 		ent.unk1 = e.unknown1;
 		ent.unk2 = e.unknown2;
 
 		if let Some(path) = &e.path {
-			let path = json_file.parent().unwrap().join(path);
+			let path = base.join(path);
 			_span.record("path", tracing::field::display(&path));
 
-			let data = std::fs::read(&path)?;
-			let mut data = match e.compress {
-				Some(method) => bzip::compress_ed6_to_vec(&data, method),
-				None => data,
+			let (data, timestamp) = match e.data {
+				Some(data) => data,
+				None => {
+					let data = std::fs::read(&path)?;
+					let timestamp = std::fs::metadata(&path)?
+						.modified()
+						.unwrap_or_else(|_| SystemTime::now());
+					(data, timestamp)
+				}
 			};
+			let mut data = e.compress.compress(data, min_savings, verify)?;
 			ent.size = data.len();
-			ent.reserved_size = e.reserve.unwrap_or(data.len());
+			ent.reserved_size = match e.reserve {
+				Some(reserve) => reserve,
+				None => e.min_reserve.unwrap_or(0).max(data.len()),
+			};
 
-			while data.len() < e.reserve.unwrap_or(0) {
+			while data.len() < ent.reserved_size {
 				data.push(0);
 			}
 
-			let timestamp = std::fs::metadata(path)?
-				.modified()
-				.unwrap_or_else(|_| SystemTime::now());
-			ent.timestamp = timestamp.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as u32;
+			ent.timestamp = dirdat::to_u32(ent.name, "timestamp", timestamp.duration_since(SystemTime::UNIX_EPOCH)?.as_secs())?;
 			Some(data)
 		} else {
 			Some(Vec::new())
@@ -153,40 +411,42 @@ fn process_entry(e: Option<Entry>, json_file: &Utf8Path) -> eyre::Result<(DirEnt
 	Ok((ent, data))
 }
 
-fn par_map<T, U>(
-	iter: impl Iterator<Item=T> + Send + 'static,
-	map: impl Fn(T) -> U + Send + Sync + 'static,
-) -> impl Iterator<Item=U> where
-	T: Send + 'static,
-	U: Send + 'static,
-{
-	use std::sync::mpsc;
-	use rayon::prelude::*;
-	let (channel_send, channel_recv) = mpsc::channel();
-	std::thread::spawn(move || {
-		iter.map_while(move |item| {
-			let (result_send, result_recv) = mpsc::sync_channel(0);
-			channel_send.send(result_recv).ok()?;
-			Some((item, result_send))
-		})
-		.par_bridge()
-		.try_for_each(|(item, result_send)| {
-			result_send.send(map(item))
-		})
-		.ok()
-	});
-	channel_recv.into_iter().map_while(|a| a.recv().ok())
+fn parse_rule(s: &str) -> Result<Rule, String> {
+	let (glob, options) = s.rsplit_once(':')
+		.ok_or("expected GLOB:compress=MODE,reserve=SIZE")?;
+	let mut rule = Rule {
+		glob: crate::util::glob(glob).map_err(|e| e.to_string())?.compile_matcher(),
+		compress: None,
+		reserve: None,
+	};
+	for option in options.split(',') {
+		match option.split_once('=') {
+			Some(("compress", "1")) => rule.compress = Some(Compression::Mode1),
+			Some(("compress", "2")) => rule.compress = Some(Compression::Mode2),
+			Some(("compress", "none")) => rule.compress = Some(Compression::None),
+			Some(("compress", "auto")) => rule.compress = Some(Compression::Auto),
+			Some(("reserve", v)) => rule.reserve = Some(v.parse().map_err(|_| format!("invalid size {v:?}"))?),
+			_ => return Err(format!("invalid option {option:?}; expected compress=1|2|none|auto or reserve=SIZE")),
+		}
+	}
+	Ok(rule)
 }
 
-fn parse_compress_mode<'de, D: serde::Deserializer<'de>>(des: D) -> Result<Option<bzip::CompressMode>, D::Error> {
-	match <Option<u8>>::deserialize(des)? {
-		Some(1) => Ok(Some(bzip::CompressMode::Mode1)),
-		Some(2) => Ok(Some(bzip::CompressMode::Mode2)),
-		None => Ok(None),
-		Some(v) => Err(de::Error::invalid_value(
-			de::Unexpected::Unsigned(v as _),
-			&"1, 2, or null"),
-		),
+fn parse_compress_mode<'de, D: serde::Deserializer<'de>>(des: D) -> Result<Compression, D::Error> {
+	#[derive(serde::Deserialize)]
+	#[serde(untagged)]
+	enum Mode {
+		Number(u64),
+		String(String),
+	}
+	let expected = &"1, 2, \"auto\", or null";
+	match <Option<Mode>>::deserialize(des)? {
+		Some(Mode::Number(1)) => Ok(Compression::Mode1),
+		Some(Mode::Number(2)) => Ok(Compression::Mode2),
+		Some(Mode::String(v)) if v == "auto" => Ok(Compression::Auto),
+		None => Ok(Compression::None),
+		Some(Mode::Number(v)) => Err(de::Error::invalid_value(de::Unexpected::Unsigned(v), expected)),
+		Some(Mode::String(v)) => Err(de::Error::invalid_value(de::Unexpected::Str(&v), expected)),
 	}
 }
 
@@ -197,10 +457,12 @@ impl std::str::FromStr for Entry {
 		Ok(Entry {
 			path: Some(Utf8PathBuf::from(s)),
 			name: None,
-			compress: None,
+			compress: Compression::None,
 			reserve: None,
+			min_reserve: None,
 			unknown1: 0,
 			unknown2: 0,
+			data: None,
 		})
 	}
 }
@@ -237,11 +499,15 @@ impl<'de> Deserialize<'de> for FileId {
 		let s = String::deserialize(des)?;
 		let err = || de::Error::invalid_value(
 			de::Unexpected::Str(&s),
-			&"a hexadecimal number",
+			&"0x followed by four hex digits, or eight including the archive number",
 		);
 
 		let s = s.strip_prefix("0x").ok_or_else(err)?;
 		let v = u32::from_str_radix(s, 16).map_err(|_| err())?;
-		Ok(FileId(v as u16))
+		match s.len() {
+			1..=4 => Ok(FileId { archive: None, id: v as u16 }),
+			8 => Ok(FileId { archive: Some((v >> 16) as u16), id: v as u16 }),
+			_ => Err(err()),
+		}
 	}
 }
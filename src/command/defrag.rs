@@ -0,0 +1,146 @@
+use std::fs::File;
+use std::io::{prelude::*, SeekFrom};
+use std::ops::Range;
+
+use camino::{Utf8PathBuf, Utf8Path};
+use clap::ValueHint;
+
+use eyre_span::emit;
+use crate::dirdat::{self, DirEntry, Name};
+
+#[derive(Debug, Clone, clap::Args)]
+#[command(arg_required_else_help = true)]
+/// Removes unused space from archives in place, without making a copy like `factoria rebuild` does.
+///
+/// Files are moved toward the start of the dat in the order they are stored in, and the dat is then truncated.
+/// Data is never overwritten while the .dir on disk still refers to it; files that would overlap their own
+/// old location are first copied to a scratch area just past the original end of the dat, which is reused
+/// for each such file. An interrupted defrag leaves a valid archive,
+/// although the offset table at the start of the dat may be out of date until defrag is run again.
+pub struct Command {
+	/// Modify the archive even if the .dat does not seem to belong with the .dir
+	#[clap(long)]
+	allow_mismatch: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	#[clap(long)]
+	wait: bool,
+
+	/// The .dir files to defragment
+	#[clap(value_hint = ValueHint::FilePath, required = true)]
+	dir_file: Vec<Utf8PathBuf>,
+}
+
+pub fn run(cmd: &Command) -> eyre::Result<()> {
+	for dir_file in &cmd.dir_file {
+		emit(defrag(cmd, dir_file));
+	}
+	Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(path=%dir_file))]
+fn defrag(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
+	let _lock = crate::util::lock(dir_file, true, cmd.wait)?;
+	let mut dir = dirdat::read_dir(&std::fs::read(dir_file)?)?;
+	let mut dat = File::options()
+		.read(true)
+		.write(true)
+		.open(dir_file.with_extension("dat"))?;
+
+	let table = crate::util::read_dat_table(&mut dat)?;
+	let dat_len = dat.seek(SeekFrom::End(0))? as usize;
+	// An interrupted defrag can leave the offset table out of date, which this rewrites anyway,
+	// so only refuse if the dir does not fit in the dat
+	let fits = table.len() == dir.len() + 1
+		&& dir.iter().all(|e| e.name == Name::default() || e.alloc_range(dat_len).is_ok());
+	crate::util::check_pair(dir_file, &dir, &table, dat_len, !cmd.allow_mismatch && !fits)?;
+
+	let mut order = Vec::new();
+	for (id, e) in dir.iter().enumerate() {
+		if e.name != Name::default() && e.offset != 0 {
+			order.push((id, e.alloc_range(dat_len)?));
+		}
+	}
+	order.sort_by_key(|(_, r)| r.start);
+
+	let header = 16 + 4 * (dir.len() + 1);
+	let mut pos = header;
+	for (id, range) in &order {
+		eyre::ensure!(range.start >= pos, "{} overlaps other data; use `factoria rebuild` instead", dir[*id].name);
+		pos = range.end;
+	}
+
+	// Old locations of files that have been moved, but are still referenced by the .dir on disk
+	let mut pending = Vec::<Range<usize>>::new();
+	let mut moved = 0;
+	let mut pos = header;
+	for (id, old) in order {
+		let _span = tracing::debug_span!("defrag_file", id=%format_args!("{id:04X}"), name=%dir[id].name).entered();
+		let len = old.len();
+		if old.start == pos {
+			pos += len;
+			continue
+		}
+
+		let dst = pos..pos + len;
+		if pending.iter().any(|r| r.start < dst.end && dst.start < r.end) {
+			commit(dir_file, &mut dat, &dir)?;
+			pending.clear();
+		}
+
+		if dst.end > old.start {
+			// Always staged at the original end, so the dat grows by at most the largest such file
+			let scratch = dat_len..dat_len + len;
+			if pending.iter().any(|r| r.start < scratch.end && scratch.start < r.end) {
+				commit(dir_file, &mut dat, &dir)?;
+				pending.clear();
+			}
+			tracing::debug!("moving via {:#X}", scratch.start);
+			copy(&mut dat, old.start, scratch.start, len)?;
+			dir[id].offset = scratch.start;
+			commit(dir_file, &mut dat, &dir)?;
+			pending.clear();
+			copy(&mut dat, scratch.start, dst.start, len)?;
+			pending.push(scratch);
+		} else {
+			copy(&mut dat, old.start, dst.start, len)?;
+			pending.push(old);
+		}
+		dir[id].offset = dst.start;
+		moved += 1;
+		pos = dst.end;
+	}
+
+	commit(dir_file, &mut dat, &dir)?;
+	dat.set_len(pos as u64)?;
+	dat.sync_all()?;
+
+	tracing::info!("moved {moved} files, {} → {} bytes", dat_len, pos);
+
+	Ok(())
+}
+
+fn copy(dat: &mut File, from: usize, to: usize, len: usize) -> eyre::Result<()> {
+	let mut buf = vec![0; len];
+	dat.seek(SeekFrom::Start(from as u64))?;
+	dat.read_exact(&mut buf)?;
+	dat.seek(SeekFrom::Start(to as u64))?;
+	dat.write_all(&buf)?;
+	Ok(())
+}
+
+/// Makes sure all data is on disk, then atomically replaces the .dir and updates the offset table.
+fn commit(dir_file: &Utf8Path, dat: &mut File, dir: &[DirEntry]) -> eyre::Result<()> {
+	dat.sync_data()?;
+
+	let tmp = dir_file.with_extension("dir.tmp");
+	let mut f = File::create(&tmp)?;
+	f.write_all(&dirdat::write_dir(dir)?)?;
+	f.sync_all()?;
+	std::fs::rename(&tmp, dir_file)?;
+
+	dat.seek(SeekFrom::Start(16))?;
+	dat.write_all(&dirdat::write_dat_table(dir)?)?;
+	dat.sync_data()?;
+	Ok(())
+}
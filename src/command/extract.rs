@@ -1,21 +1,26 @@
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use camino::{Utf8PathBuf, Utf8Path};
 use clap::ValueHint;
 
 use eyre_span::emit;
-use indicatif::ParallelProgressIterator;
+use indicatif::{ParallelProgressIterator, ProgressIterator};
 use rayon::prelude::*;
 
 use falcompress::bzip;
-use crate::dirdat;
+use crate::dirdat::{self, DirEntry};
 use crate::util::mmap;
 
 #[derive(Debug, Clone, clap::Args)]
 #[command(arg_required_else_help = true)]
 /// Extracts files from an archive into a directory.
 ///
-/// Files will be placed in a directory with the name of the archive.
+/// Files will be placed in a directory with the name of the archive,
+/// or in a .tar or .zip file if --to is given.
 pub struct Command {
 	/// Directory to place extracted files in.
 	#[clap(long, short, value_hint = ValueHint::DirPath)]
@@ -29,13 +34,125 @@ pub struct Command {
 	/// Do not attempt to decompress files.
 	#[clap(short='C', long)]
 	compressed: bool,
+	/// Only write files that have changed since a previous extraction.
+	///
+	/// The extracted files are recorded in a hidden manifest in the output directory.
+	#[clap(short, long, value_enum, require_equals = true, num_args=0..=1, default_missing_value="time")]
+	update: Option<Update>,
+	/// With --update, remove files from previous extractions that are no longer in the archive.
+	///
+	/// Only files listed in the manifest are removed; anything else in the output directory is left alone.
+	/// Nothing is removed if --glob is given.
+	#[clap(long, requires = "update")]
+	delete: bool,
+	/// Write the files into a .tar or .zip archive instead of a directory.
+	///
+	/// If several archives are extracted, each gets its own directory inside it.
+	#[clap(long, value_hint = ValueHint::FilePath, conflicts_with_all = ["output", "update"])]
+	to: Option<Utf8PathBuf>,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	///
+	/// Reading an archive does not create its .lock file, so this only has an effect once a command
+	/// that modifies the archive has left one next to it.
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+	/// What to do when several files have the same name
+	#[clap(long, value_enum, default_value = "suffix")]
+	duplicates: Duplicates,
+	/// How to arrange the extracted files
+	#[clap(long, value_enum, default_value = "flat")]
+	layout: Layout,
 
 	/// The .dir file(s) to extract.
 	#[clap(value_hint = ValueHint::FilePath, required = true)]
 	dir_file: Vec<Utf8PathBuf>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Duplicates {
+	/// Do not extract anything from the archive
+	Error,
+	/// Add the file id to the names of all but the first
+	Suffix,
+	/// Only extract the first file with each name
+	First,
+	/// Only extract the last file with each name
+	Last,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Layout {
+	/// <archive>/<name>
+	Flat,
+	/// <archive>/<id>_<name>, which preserves the order of the files
+	Id,
+	/// <archive>/<extension>/<name>
+	Ext,
+	/// Like flat, but always in a subdirectory per archive, even if only extracting one
+	Merged,
+}
+
+impl Layout {
+	/// The path of a file, relative to the archive's directory.
+	pub fn path(self, id: usize, name: &str) -> String {
+		match self {
+			Layout::Flat | Layout::Merged => name.to_owned(),
+			Layout::Id => format!("{id:04X}_{name}"),
+			Layout::Ext => match name.split_once('.') {
+				Some((_, ext)) if !ext.is_empty() => format!("{ext}/{name}"),
+				_ => name.to_owned(),
+			},
+		}
+	}
+
+	/// Recovers an archive name from a file name in this layout.
+	///
+	/// With an id prefix, a `~NNNN` suffix for the same id added by `--duplicates=suffix` is removed too,
+	/// since the id already tells the files apart.
+	pub fn name(self, file_name: &str) -> Cow<'_, str> {
+		let Some((id, name)) = self.split_id(file_name) else { return Cow::Borrowed(file_name) };
+		let suffix = format!("~{id:04X}");
+		match name.split_once('.') {
+			Some((stem, ext)) if stem.ends_with(&suffix) => {
+				Cow::Owned(format!("{}.{ext}", &stem[..stem.len() - suffix.len()]))
+			}
+			None if name.ends_with(&suffix) => Cow::Borrowed(&name[..name.len() - suffix.len()]),
+			_ => Cow::Borrowed(name),
+		}
+	}
+
+	/// Recovers the file's id from a file name in this layout, if it has one.
+	pub fn id(self, file_name: &str) -> Option<usize> {
+		self.split_id(file_name).map(|(id, _)| id)
+	}
+
+	fn split_id(self, file_name: &str) -> Option<(usize, &str)> {
+		match (self, file_name.split_once('_')) {
+			(Layout::Id, Some((id, name))) if id.len() == 4 && id.bytes().all(|b| b.is_ascii_hexdigit()) => {
+				Some((usize::from_str_radix(id, 16).ok()?, name))
+			}
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Update {
+	/// Skip files with the same size and modification time
+	Time,
+	/// Skip files with the same contents
+	Content,
+}
+
 pub fn run(cmd: &Command) -> eyre::Result<()> {
+	if let Some(to) = &cmd.to {
+		return extract_to(cmd, to)
+	}
 	for dir_file in &cmd.dir_file {
 		emit(extract(cmd, dir_file));
 	}
@@ -44,23 +161,21 @@ pub fn run(cmd: &Command) -> eyre::Result<()> {
 
 #[tracing::instrument(skip_all, fields(path=%dir_file))]
 fn extract(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
+	let _lock = crate::util::lock(dir_file, false, cmd.wait)?;
 	let dir_entries = dirdat::read_dir(&std::fs::read(dir_file)?)?;
 	let dat = mmap(&dir_file.with_extension("dat"))?;
+	if let Ok(table) = dirdat::read_dat(&dat) {
+		crate::util::check_pair(dir_file, &dir_entries, &table, dat.len(), false)?;
+	}
 
-	let outdir = crate::util::output(cmd.output.as_deref(), dir_file, "", cmd.dir_file.len())?;
+	let n_inputs = match cmd.layout {
+		Layout::Merged => usize::MAX,
+		_ => cmd.dir_file.len(),
+	};
+	let outdir = crate::util::output(cmd.output.as_deref(), dir_file, "", n_inputs)?;
 	std::fs::create_dir_all(&outdir)?;
 
-	let mut globset = globset::GlobSetBuilder::new();
-	for glob in &cmd.glob {
-		globset.add(glob.clone());
-	}
-	let globset = globset.build()?;
-
-	let dir_entries = dir_entries.into_iter()
-		.filter(|e| e.name != dirdat::Name::default())
-		.filter(|e| cmd.all || e.timestamp != 0)
-		.filter(|e| globset.is_empty() || globset.is_match(e.name.to_string()))
-		.collect::<Vec<_>>();
+	let dir_entries = select(cmd, dir_entries)?;
 
 	let span = tracing::Span::current();
 	let style = indicatif::ProgressStyle::with_template("{bar} {prefix} {pos}/{len}").unwrap()
@@ -68,25 +183,336 @@ fn extract(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
 	let ind = indicatif::ProgressBar::new(dir_entries.len() as _)
 		.with_style(style)
 		.with_prefix(dir_file.to_string());
-	dir_entries.par_iter().progress_with(ind.clone()).for_each(|e| {
+	let written = AtomicUsize::new(0);
+	let skipped = AtomicUsize::new(0);
+	dir_entries.par_iter().progress_with(ind.clone()).for_each(|(_, e, path)| {
 		emit(try {
 			let _span = tracing::info_span!(parent: &span, "extract_file", name=%e.name).entered();
-			let outfile = &outdir.join(e.name.to_string());
-			let Some(rawdata) = dat.get(e.offset..e.offset+e.size) else {
-				tracing::error!("invalid range");
+			let outfile = &outdir.join(path);
+			let mtime = filetime::FileTime::from_unix_time(e.timestamp as _, 0);
+
+			if cmd.update == Some(Update::Time) && same_size_and_time(outfile, e, &dat, cmd.compressed)? {
+				skipped.fetch_add(1, Ordering::Relaxed);
 				return
-			};
-			let data = if !cmd.compressed && bzip::compression_info_ed6(rawdata).is_some() {
-				Cow::Owned(bzip::decompress_ed6_from_slice(rawdata)?)
-			} else {
-				Cow::Borrowed(rawdata)
-			};
+			}
 
+			let data = read_entry(e, &dat, cmd.compressed)?;
+
+			if cmd.update == Some(Update::Content) && std::fs::read(outfile).is_ok_and(|a| a == *data) {
+				filetime::set_file_mtime(outfile, mtime)?;
+				skipped.fetch_add(1, Ordering::Relaxed);
+				return
+			}
+
+			if let Some(parent) = outfile.parent() {
+				std::fs::create_dir_all(parent)?;
+			}
 			std::fs::write(outfile, data)?;
-			filetime::set_file_mtime(outfile, filetime::FileTime::from_unix_time(e.timestamp as _, 0))?;
+			filetime::set_file_mtime(outfile, mtime)?;
+			written.fetch_add(1, Ordering::Relaxed);
 		});
 	});
 	ind.abandon();
 
+	if cmd.update.is_some() {
+		let manifest = outdir.join(format!(".{}.manifest", dir_file.file_stem().unwrap_or_default()));
+		let previous = read_manifest(&manifest)?;
+		let expected = dir_entries.iter()
+			.map(|(_, _, path)| path.clone())
+			.collect::<BTreeSet<_>>();
+		let (removed, recorded) = if cmd.delete && cmd.glob.is_empty() {
+			(remove_stale(&outdir, &previous, &expected)?, expected)
+		} else {
+			(0, previous.union(&expected).cloned().collect())
+		};
+		write_manifest(&manifest, &recorded)?;
+		tracing::info!(
+			"{} written, {} unchanged, {} removed",
+			written.into_inner(),
+			skipped.into_inner(),
+			removed,
+		);
+	}
+
 	Ok(())
 }
+
+#[tracing::instrument(skip_all, fields(path=%to))]
+fn extract_to(cmd: &Command, to: &Utf8Path) -> eyre::Result<()> {
+	let mut out = Container::create(to)?;
+	for dir_file in &cmd.dir_file {
+		emit(extract_into(cmd, dir_file, &mut out));
+	}
+	out.finish()?;
+	tracing::info!("created");
+	Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(path=%dir_file))]
+fn extract_into(cmd: &Command, dir_file: &Utf8Path, out: &mut Container) -> eyre::Result<()> {
+	let _lock = crate::util::lock(dir_file, false, cmd.wait)?;
+	let dir_entries = dirdat::read_dir(&std::fs::read(dir_file)?)?;
+	let dat = Arc::new(mmap(&dir_file.with_extension("dat"))?);
+	if let Ok(table) = dirdat::read_dat(&dat) {
+		crate::util::check_pair(dir_file, &dir_entries, &table, dat.len(), false)?;
+	}
+
+	let prefix = if cmd.layout == Layout::Merged || cmd.dir_file.len() > 1 {
+		let stem = dir_file.file_stem().ok_or_else(|| eyre::eyre!("file has no name"))?;
+		format!("{stem}/")
+	} else {
+		String::new()
+	};
+
+	let dir_entries = select(cmd, dir_entries)?;
+
+	let style = indicatif::ProgressStyle::with_template("{bar} {prefix} {pos}/{len}").unwrap()
+		.progress_chars("█🮆🮅🮄▀🮃🮂▔ ");
+	let ind = indicatif::ProgressBar::new(dir_entries.len() as _)
+		.with_style(style)
+		.with_prefix(dir_file.to_string());
+
+	// Decompression happens in parallel; anything that is stored as is gets copied straight from the dat.
+	let iter = crate::util::par_map(dir_entries.into_iter(), {
+		let dat = dat.clone();
+		let raw = cmd.compressed;
+		move |(_, e, path)| {
+			let data = read_entry(&e, &dat, raw).map(|data| match data {
+				Cow::Owned(data) => Some(data),
+				Cow::Borrowed(_) => None,
+			});
+			(e, path, data)
+		}
+	});
+	for (e, path, data) in iter.progress_with(ind.clone()) {
+		let _span = tracing::info_span!("extract_file", name=%e.name).entered();
+		let data: eyre::Result<Cow<[u8]>> = try {
+			match data? {
+				Some(data) => Cow::Owned(data),
+				None => Cow::Borrowed(&dat[e.data_range(dat.len())?]),
+			}
+		};
+		if let Some(data) = emit(data) {
+			out.add(&format!("{prefix}{path}"), &data, e.timestamp)?;
+		}
+	}
+	ind.abandon();
+
+	Ok(())
+}
+
+/// A .tar or .zip file being written by `--to`.
+enum Container {
+	Tar(tar::Builder<BufWriter<std::fs::File>>),
+	Zip(zip::ZipWriter<BufWriter<std::fs::File>>),
+}
+
+impl Container {
+	fn create(path: &Utf8Path) -> eyre::Result<Self> {
+		let ext = path.extension().map(str::to_lowercase);
+		eyre::ensure!(
+			matches!(ext.as_deref(), Some("tar" | "zip")),
+			"can only extract to .tar or .zip files",
+		);
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		let file = BufWriter::new(std::fs::File::create(path)?);
+		Ok(match ext.as_deref() {
+			Some("tar") => Container::Tar(tar::Builder::new(file)),
+			_ => Container::Zip(zip::ZipWriter::new(file)),
+		})
+	}
+
+	fn add(&mut self, path: &str, data: &[u8], timestamp: u32) -> eyre::Result<()> {
+		match self {
+			Container::Tar(tar) => {
+				let mut header = tar::Header::new_gnu();
+				header.set_entry_type(tar::EntryType::Regular);
+				header.set_size(data.len() as u64);
+				header.set_mode(0o644);
+				header.set_mtime(timestamp as u64);
+				tar.append_data(&mut header, path, data)?;
+			}
+			Container::Zip(zip) => {
+				use chrono::{Datelike, Timelike};
+				let ts = chrono::NaiveDateTime::from_timestamp_opt(timestamp as i64, 0).unwrap();
+				// Zip can only represent 1980 to 2107; anything else gets the earliest possible time
+				let mtime = zip::DateTime::from_date_and_time(
+					ts.year() as u16, ts.month() as u8, ts.day() as u8,
+					ts.hour() as u8, ts.minute() as u8, ts.second() as u8,
+				).unwrap_or_default();
+				let options = zip::write::FileOptions::default()
+					.compression_method(zip::CompressionMethod::Deflated)
+					.last_modified_time(mtime);
+				zip.start_file(path, options)?;
+				zip.write_all(data)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn finish(self) -> eyre::Result<()> {
+		match self {
+			Container::Tar(tar) => tar.into_inner()?.flush()?,
+			Container::Zip(mut zip) => zip.finish()?.flush()?,
+		}
+		Ok(())
+	}
+}
+
+/// Picks the entries to extract and the paths to extract them to.
+fn select(cmd: &Command, dir_entries: Vec<DirEntry>) -> eyre::Result<Vec<(usize, DirEntry, String)>> {
+	let mut globset = globset::GlobSetBuilder::new();
+	for glob in &cmd.glob {
+		globset.add(glob.clone());
+	}
+	let globset = globset.build()?;
+
+	let dir_entries = dir_entries.into_iter()
+		.enumerate()
+		.filter(|(_, e)| e.name != dirdat::Name::default())
+		.filter(|(_, e)| cmd.all || e.timestamp != 0)
+		.filter(|(_, e)| globset.is_empty() || globset.is_match(e.name.decode(cmd.encoding)))
+		.collect::<Vec<_>>();
+	file_names(dir_entries, cmd.duplicates, cmd.layout, cmd.encoding)
+}
+
+/// Whether the file at `path` has the size and modification time that extracting `e` would give it.
+fn same_size_and_time(path: &Utf8Path, e: &DirEntry, dat: &[u8], raw: bool) -> eyre::Result<bool> {
+	let Ok(meta) = std::fs::metadata(path) else { return Ok(false) };
+	let rawdata = &dat[e.data_range(dat.len())?];
+	let size = match bzip::compression_info_ed6(rawdata) {
+		Some(info) if !raw => info.0,
+		_ => rawdata.len(),
+	};
+	let mtime = filetime::FileTime::from_last_modification_time(&meta);
+	Ok(meta.len() == size as u64 && mtime.unix_seconds() == e.timestamp as i64)
+}
+
+/// Whether a file is one of the manifests written by `--update`, which are not part of the extracted files.
+pub(crate) fn is_manifest(file_name: &str) -> bool {
+	file_name.starts_with('.') && file_name.ends_with(".manifest")
+}
+
+/// Reads the paths recorded by previous extractions, relative to the output directory.
+fn read_manifest(path: &Utf8Path) -> eyre::Result<BTreeSet<String>> {
+	match std::fs::read_to_string(path) {
+		Ok(text) => Ok(text.lines().filter(|l| !l.is_empty()).map(str::to_owned).collect()),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+		Err(e) => Err(e.into()),
+	}
+}
+
+fn write_manifest(path: &Utf8Path, paths: &BTreeSet<String>) -> eyre::Result<()> {
+	let mut text = String::new();
+	for p in paths {
+		text.push_str(p);
+		text.push('\n');
+	}
+	std::fs::write(path, text)?;
+	Ok(())
+}
+
+/// Removes the files in `previous` that are not in `expected`, returning how many were removed.
+///
+/// Directories left empty by this are removed too. Paths that would lead outside `dir` are ignored.
+fn remove_stale(dir: &Utf8Path, previous: &BTreeSet<String>, expected: &BTreeSet<String>) -> eyre::Result<usize> {
+	let mut removed = 0;
+	for path in previous.difference(expected) {
+		let path = Utf8Path::new(path);
+		if !path.components().all(|c| matches!(c, camino::Utf8Component::Normal(_))) {
+			tracing::warn!("ignoring {path} in manifest");
+			continue
+		}
+		let file = dir.join(path);
+		match std::fs::remove_file(&file) {
+			Ok(()) => {
+				tracing::info!("removed {file}");
+				removed += 1;
+			}
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Err(e) => return Err(e.into()),
+		}
+		for parent in file.ancestors().skip(1).take_while(|p| *p != dir) {
+			if std::fs::remove_dir(parent).is_err() {
+				break
+			}
+		}
+	}
+	Ok(removed)
+}
+
+/// Decides which path each entry is extracted to, resolving duplicates according to `policy`.
+///
+/// Paths are compared case-insensitively, since that is what matters on some file systems.
+pub(crate) fn file_names(
+	entries: Vec<(usize, DirEntry)>,
+	policy: Duplicates,
+	layout: Layout,
+	encoding: dirdat::Encoding,
+) -> eyre::Result<Vec<(usize, DirEntry, String)>> {
+	let mut paths = entries.iter()
+		.map(|(id, e)| layout.path(*id, &e.name.decode(encoding)))
+		.collect::<Vec<_>>();
+	let mut keep = vec![true; entries.len()];
+
+	let mut groups = BTreeMap::<String, Vec<usize>>::new();
+	for (i, path) in paths.iter().enumerate() {
+		groups.entry(path.to_lowercase()).or_default().push(i);
+	}
+
+	let mut failed = false;
+	for group in groups.values().filter(|g| g.len() > 1) {
+		let ids = group.iter()
+			.map(|&i| format!("{:04X}", entries[i].0))
+			.collect::<Vec<_>>()
+			.join(", ");
+		let (first, last) = (group[0], group[group.len() - 1]);
+		match policy {
+			Duplicates::Error => {
+				tracing::error!("{} is used by {ids}", paths[first]);
+				failed = true;
+			}
+			Duplicates::Suffix => {
+				for &i in &group[1..] {
+					let (id, e) = &entries[i];
+					let name = e.name.decode(encoding);
+					let name = match name.split_once('.') {
+						Some((stem, ext)) => format!("{stem}~{id:04X}.{ext}"),
+						None => format!("{name}~{id:04X}"),
+					};
+					let new = layout.path(*id, &name);
+					tracing::warn!("{} is used by {ids}; extracting {id:04X} as {new}", paths[i]);
+					paths[i] = new;
+				}
+			}
+			Duplicates::First => {
+				tracing::warn!("{} is used by {ids}; extracting only {:04X}", paths[first], entries[first].0);
+				group[1..].iter().for_each(|&i| keep[i] = false);
+			}
+			Duplicates::Last => {
+				tracing::warn!("{} is used by {ids}; extracting only {:04X}", paths[last], entries[last].0);
+				group[..group.len() - 1].iter().for_each(|&i| keep[i] = false);
+			}
+		}
+	}
+	eyre::ensure!(!failed, "archive has duplicate names; use --duplicates to choose how to handle them");
+
+	Ok(entries.into_iter()
+		.zip(paths)
+		.zip(keep)
+		.filter(|(_, keep)| *keep)
+		.map(|(((id, e), path), _)| (id, e, path))
+		.collect())
+}
+
+/// Reads an entry's data from the .dat file, decompressing it unless `raw` is set.
+pub fn read_entry<'a>(e: &DirEntry, dat: &'a [u8], raw: bool) -> eyre::Result<Cow<'a, [u8]>> {
+	let rawdata = &dat[e.data_range(dat.len())?];
+	if !raw && bzip::compression_info_ed6(rawdata).is_some() {
+		Ok(Cow::Owned(bzip::decompress_ed6_from_slice(rawdata)?))
+	} else {
+		Ok(Cow::Borrowed(rawdata))
+	}
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::io::{self, BufWriter, Write};
 
 use camino::{Utf8PathBuf, Utf8Path};
@@ -8,14 +9,18 @@ use eyre_span::emit;
 
 use falcompress::bzip;
 use crate::dirdat::{self, DirEntry, Name};
+use super::extract::{Duplicates, Layout};
 
 #[derive(Debug, Clone, clap::Args)]
 #[command(arg_required_else_help = true)]
 /// Produces a json file listing all the files in an archive.
 ///
 /// Combined with the `extract` command, this is enough for `create` to recreate an identical archive.
+/// If files were extracted with `--layout`, `--all`, or `--duplicates`, the same options must be given here,
+/// so that the index refers to the same paths as the extracted files.
 ///
-/// Note that while this writes file ids with eight hex digits, only the lower four are used when reconstructing.
+/// For archives named like `ED6_DT01.dir`, file ids are written with eight hex digits, where the upper four are the archive number.
+/// When reconstructing, only the lower four are used, but all ids in one index must have the same archive number.
 pub struct Command {
 	/// Do not attempt to infer compression mode.
 	///
@@ -23,6 +28,35 @@ pub struct Command {
 	#[clap(short='C', long)]
 	compressed: bool,
 
+	/// Record the compression of all files as `auto`, rather than the mode they currently use.
+	///
+	/// This way `create` makes the same choice each time, even if the files are edited.
+	#[clap(long, conflicts_with = "compressed")]
+	auto: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	///
+	/// Reading an archive does not create its .lock file, so this only has an effect once a command
+	/// that modifies the archive has left one next to it.
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
+	/// Layout the files were extracted with
+	#[clap(long, value_enum, default_value = "flat")]
+	layout: Layout,
+
+	/// Whether zero-sized files were extracted with `extract --all`
+	#[clap(short, long)]
+	all: bool,
+
+	/// How duplicate names were handled when extracting
+	#[clap(long, value_enum, default_value = "suffix")]
+	duplicates: Duplicates,
+
 	/// Where to place the resulting json file.
 	///
 	/// As a special case, if this is `-`, the json is written to stdout.
@@ -43,15 +77,29 @@ pub fn run(cmd: &Command) -> eyre::Result<()> {
 
 #[tracing::instrument(skip_all, fields(path=%dir_file, out))]
 fn index(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
+	let _lock = crate::util::lock(dir_file, false, cmd.wait)?;
 	let dir = dirdat::read_dir(&std::fs::read(dir_file)?)?;
-	let dat = if !cmd.compressed {
+	let dat = if !cmd.compressed && !cmd.auto {
 		Some(crate::util::mmap(&dir_file.with_extension("dat"))?)
 	} else {
 		None
 	};
 	let dat = dat.as_deref();
+	if let Some(dat) = dat {
+		if let Ok(table) = dirdat::read_dat(dat) {
+			crate::util::check_pair(dir_file, &dir, &table, dat.len(), false)?;
+		}
+	}
 	let archive_number = super::list::get_archive_number(dir_file);
 
+	let files = dir.iter().cloned().enumerate()
+		.filter(|(_, e)| e.name != Name::default() && (cmd.all || e.timestamp != 0))
+		.collect();
+	let paths = super::extract::file_names(files, cmd.duplicates, cmd.layout, cmd.encoding)?
+		.into_iter()
+		.map(|(id, _, path)| (id, format!("{}/{}", dir_file.file_stem().unwrap(), path)))
+		.collect::<HashMap<_, _>>();
+
 	let json = dir.iter().enumerate().map(|(id, ent)| {
 		let _span = tracing::debug_span!("index_file", id=%format_args!("{id:04X}"), name=%ent.name).entered();
 		let mut key = String::from("0x");
@@ -60,7 +108,7 @@ fn index(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
 		}
 		key.push_str(&format!("{:04X}", id));
 
-		(key, index_file(ent, dir_file, dat))
+		(key, index_file(ent, paths.get(&id).map(String::as_str), dat, cmd))
 	}).collect::<Value>();
 
 	let out = if cmd.output.as_ref().is_some_and(|a| a == "-") {
@@ -83,24 +131,33 @@ fn index(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
 	Ok(())
 }
 
-fn index_file(m: &DirEntry, dir_file: &Utf8Path, dat: Option<&[u8]>) -> Value {
+fn index_file(m: &DirEntry, path: Option<&str>, dat: Option<&[u8]>, cmd: &Command) -> Value {
 	if m.name == Name::default() {
 		Value::Null
 	} else {
 		let mut o = serde_json::Map::new();
 
-		if m.timestamp == 0 {
-			o.insert("path".into(), Value::Null);
-			o.insert("name".into(), m.name.to_string().into());
-		} else {
-			o.insert("path".into(), format!("{}/{}", dir_file.file_stem().unwrap(), m.name).into());
-			let comp = dat.and_then(|a| a.get(m.offset..m.offset+m.size)).and_then(bzip::compression_info_ed6);
-			if let Some(comp) = comp {
+		let name = m.name.to_escaped(cmd.encoding);
+		if let Some(path) = path {
+			o.insert("path".into(), path.into());
+			// Needed for escaped names, duplicates, and some layouts
+			if path.rsplit_once('/').map_or(path, |a| a.1) != name {
+				o.insert("name".into(), name.into());
+			}
+			let comp = dat
+				.and_then(|a| Some(&a[m.data_range(a.len()).ok()?]))
+				.and_then(bzip::compression_info_ed6);
+			if cmd.auto {
+				o.insert("compress".into(), "auto".into());
+			} else if let Some(comp) = comp {
 				match comp.1.unwrap_or_default() {
 					bzip::CompressMode::Mode1 => o.insert("compress".into(), 1u8.into()),
 					bzip::CompressMode::Mode2 => o.insert("compress".into(), 2u8.into()),
 				};
 			}
+		} else {
+			o.insert("path".into(), Value::Null);
+			o.insert("name".into(), name.into());
 		}
 
 		if m.reserved_size != m.size {
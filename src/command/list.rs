@@ -57,6 +57,17 @@ pub struct Command {
 	#[clap(short='c', long)]
 	compressed_size: bool,
 
+	/// If the archive is in use by another process, wait for it instead of failing
+	///
+	/// Reading an archive does not create its .lock file, so this only has an effect once a command
+	/// that modifies the archive has left one next to it.
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
 	/// Show file id in short modes (always shown in -l)
 	#[clap(short, long)]
 	id: bool,
@@ -65,6 +76,10 @@ pub struct Command {
 	#[clap(short, long)]
 	unix: bool,
 
+	/// Show names that do not convert cleanly in the escaped form accepted by other commands
+	#[clap(short, long)]
+	escape: bool,
+
 	/// The .dir file(s) to inspect.
 	#[clap(value_hint = ValueHint::FilePath, required = true)]
 	dir_file: Vec<Utf8PathBuf>,
@@ -82,13 +97,17 @@ pub enum SortColumn {
 
 #[derive(Debug)]
 pub struct Entry {
-	dirent: DirEntry,
-	index: u16,
-	decompressed_size: Option<usize>,
-	compression_mode: Option<bzip::CompressMode>,
-	weird_start: bool,
-	weird_end: bool,
-	weird_dat_offset: bool,
+	pub(crate) dirent: DirEntry,
+	pub(crate) index: u16,
+	pub(crate) decompressed_size: Option<usize>,
+	pub(crate) compression_mode: Option<bzip::CompressMode>,
+	pub(crate) weird_start: bool,
+	pub(crate) weird_end: bool,
+	pub(crate) weird_dat_offset: bool,
+	/// The slot after this entry's in the offset table is not where its data ends.
+	///
+	/// Only checked when that slot does not belong to another file, which sets it to its own offset.
+	pub(crate) weird_dat_end: bool,
 }
 
 impl std::ops::Deref for Entry {
@@ -195,11 +214,14 @@ fn format_entry_long(cmd: &Command, archive_number: Option<u8>, e: &Entry, cells
 		(false, true) => "▁",
 		(true, true)  => "🮀",
 	};
-	if flags != " " || e.weird_dat_offset {
+	if flags != " " || e.weird_dat_offset || e.weird_dat_end {
 		s.push_str("\x1B[31m");
 		if e.weird_dat_offset {
 			s.push('•')
 		}
+		if e.weird_dat_end {
+			s.push('◦')
+		}
 		s.push_str(flags);
 		s.push_str("\x1B[m");
 	}
@@ -233,9 +255,13 @@ fn format_entry_long(cmd: &Command, archive_number: Option<u8>, e: &Entry, cells
 	cells.push(Cell::left(format_name(cmd, e)));
 }
 
-fn format_name(_cmd: &Command, e: &Entry) -> String {
+fn format_name(cmd: &Command, e: &Entry) -> String {
 	let mut s = String::new();
-	let name = e.name.to_string();
+	let name = if cmd.escape {
+		e.name.to_escaped(cmd.encoding)
+	} else {
+		e.name.decode(cmd.encoding)
+	};
 	let ext = name.split_once('.').map_or("", |a| a.1);
 	if let Some(color) = get_color(ext) {
 		s.push_str(&format!("\x1B[38;5;{color}m"))
@@ -264,11 +290,15 @@ fn format_size(cmd: &Command, e: &Entry) -> String {
 }
 
 fn format_size2(cmd: &Command, size: usize) -> String {
-	if cmd.bytes {
+	format_bytes(size, cmd.binary, cmd.bytes)
+}
+
+pub(crate) fn format_bytes(size: usize, binary: bool, bytes: bool) -> String {
+	if bytes {
 		size.to_string()
 	} else {
 		use number_prefix::NumberPrefix as NP;
-		let n = if cmd.binary {
+		let n = if binary {
 			NP::binary(size as f64)
 		} else {
 			NP::decimal(size as f64)
@@ -317,9 +347,58 @@ fn get_entries(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<Vec<Entry>> {
 	}
 	let globset = globset.build()?;
 
+	let probe = !cmd.compressed && (cmd.size || cmd.long || cmd.sort == SortColumn::Size);
+	let mut entries = read_entries(dir_file, probe, cmd.wait)?;
+
+	if !cmd.actually_all {
+		entries.retain(|e| e.name != dirdat::Name::default());
+	}
+	if !cmd.actually_all && !cmd.all {
+		entries.retain(|e| e.timestamp != 0);
+	}
+	if !globset.is_empty() {
+		entries.retain(|e| globset.is_match(e.name.decode(cmd.encoding)));
+	}
+
+	match cmd.sort {
+		SortColumn::Id => {},
+		SortColumn::Name => entries.sort_by_key(|e| e.name.decode(cmd.encoding)),
+		SortColumn::Size => entries.sort_by_key(|e| e.decompressed_size.unwrap_or(e.size)),
+		SortColumn::CSize => entries.sort_by_key(|e| e.size),
+		SortColumn::Time => entries.sort_by_key(|e| e.timestamp),
+		SortColumn::Ext => entries.sort_by(|a, b| {
+			let a = a.name.decode(cmd.encoding);
+			let b = b.name.decode(cmd.encoding);
+			let a = (a.split_once('.').map(|a| a.1), &a);
+			let b = (b.split_once('.').map(|b| b.1), &b);
+			a.cmp(&b)
+		}),
+	}
+
+	if cmd.reverse {
+		entries.reverse();
+	}
+
+	Ok(entries)
+}
+
+/// Reads all entries in an archive, including placeholders, and checks them for oddities.
+///
+/// If `probe` is set, the compression of each file is also determined.
+/// `wait` is passed on to [`crate::util::lock`].
+pub(crate) fn read_entries(dir_file: &Utf8Path, probe: bool, wait: bool) -> eyre::Result<Vec<Entry>> {
+	let _lock = crate::util::lock(dir_file, false, wait)?;
 	let dat = emit(mmap(&dir_file.with_extension("dat")));
 
-	let mut entries = dirdat::read_dir(&std::fs::read(dir_file)?)?
+	let dir = dirdat::read_dir(&std::fs::read(dir_file)?)?;
+	if let Some(dat) = &dat {
+		match dirdat::read_dat(dat) {
+			Ok(table) => crate::util::check_pair(dir_file, &dir, &table, dat.len(), false)?,
+			Err(_) => tracing::warn!("{} is not a valid dat file", dir_file.with_extension("dat")),
+		}
+	}
+
+	let mut entries = dir
 		.into_iter()
 		.enumerate()
 		.map(|(index, dirent)| Entry {
@@ -330,6 +409,7 @@ fn get_entries(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<Vec<Entry>> {
 			weird_start: false,
 			weird_end: false,
 			weird_dat_offset: false,
+			weird_dat_end: false,
 		})
 		.collect::<Vec<_>>();
 
@@ -351,49 +431,29 @@ fn get_entries(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<Vec<Entry>> {
 				e.weird_dat_offset = f.u32_le().ok() != Some(e.offset as u32);
 			}
 		}
-	}
 
-	if !cmd.actually_all {
-		entries.retain(|e| e.name != dirdat::Name::default());
-	}
-	if !cmd.actually_all && !cmd.all {
-		entries.retain(|e| e.timestamp != 0);
-	}
-	if !globset.is_empty() {
-		entries.retain(|e| globset.is_match(e.name.to_string()));
+		let table = dirdat::read_dat(dat).unwrap_or_default();
+		let stored = |e: &Entry| e.name != dirdat::Name::default() && e.offset != 0;
+		for id in 0..entries.len() {
+			if stored(&entries[id]) && !entries.get(id + 1).is_some_and(stored) {
+				let end = entries[id].offset + entries[id].size.max(entries[id].reserved_size);
+				entries[id].weird_dat_end = table.get(id + 1) != Some(&end);
+			}
+		}
 	}
 
-	if !cmd.compressed && (cmd.size || cmd.long || cmd.sort == SortColumn::Size) {
+	if probe {
 		if let Some(dat) = &dat {
 			for m in &mut entries {
 				if m.timestamp == 0 { continue }
-				let Some(data) = dat.get(m.offset..m.offset+m.size) else { continue };
-				let Some(info) = bzip::compression_info_ed6(data) else { continue };
+				let Ok(range) = m.data_range(dat.len()) else { continue };
+				let Some(info) = bzip::compression_info_ed6(&dat[range]) else { continue };
 				m.decompressed_size = Some(info.0);
 				m.compression_mode = info.1;
 			}
 		}
 	}
 
-	match cmd.sort {
-		SortColumn::Id => {},
-		SortColumn::Name => entries.sort_by_key(|e| e.name.to_string()),
-		SortColumn::Size => entries.sort_by_key(|e| e.decompressed_size.unwrap_or(e.size)),
-		SortColumn::CSize => entries.sort_by_key(|e| e.size),
-		SortColumn::Time => entries.sort_by_key(|e| e.timestamp),
-		SortColumn::Ext => entries.sort_by(|a, b| {
-			let a = a.name.to_string();
-			let b = b.name.to_string();
-			let a = (a.split_once('.').map(|a| a.1), &a);
-			let b = (b.split_once('.').map(|b| b.1), &b);
-			a.cmp(&b)
-		}),
-	}
-
-	if cmd.reverse {
-		entries.reverse();
-	}
-
 	Ok(entries)
 }
 
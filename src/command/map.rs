@@ -0,0 +1,247 @@
+use std::fmt::Write as _;
+
+use camino::{Utf8PathBuf, Utf8Path};
+use clap::ValueHint;
+
+use eyre_span::emit;
+use crate::dirdat::{self, DirEntry};
+use super::list::format_bytes;
+
+#[derive(Debug, Clone, clap::Args)]
+#[command(arg_required_else_help = true)]
+/// Draws a map of how space is used in archives' .dat files.
+///
+/// Each character of the map stands for an equal share of the file, and shows what most of that share is used for:
+/// the offset table at the start, file data, padding reserved for files to grow into, or gaps that are not used by anything.
+/// Gaps can be reclaimed with `factoria rebuild`, and padding with `factoria rebuild --pack`.
+pub struct Command {
+	/// Width of the map, defaulting to the terminal width
+	#[clap(short, long)]
+	width: Option<usize>,
+	/// Number of lines to draw the map on
+	#[clap(short, long, default_value_t = 1)]
+	lines: usize,
+
+	/// Also write the map as an svg image, which shows each file's extent
+	#[clap(long, value_hint = ValueHint::FilePath)]
+	svg: Option<Utf8PathBuf>,
+
+	/// Use binary prefixes instead of SI for file sizes
+	#[clap(short, long)]
+	binary: bool,
+	/// Display raw number of bytes for file sizes
+	#[clap(short='B', long, overrides_with("binary"))]
+	bytes: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	///
+	/// Reading an archive does not create its .lock file, so this only has an effect once a command
+	/// that modifies the archive has left one next to it.
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
+	/// The .dir file(s) to inspect.
+	#[clap(value_hint = ValueHint::FilePath, required = true)]
+	dir_file: Vec<Utf8PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+	Header,
+	Data,
+	Padding,
+	Gap,
+}
+
+impl Kind {
+	const ALL: [Kind; 4] = [Kind::Header, Kind::Data, Kind::Padding, Kind::Gap];
+
+	fn glyph(self) -> &'static str {
+		match self {
+			Kind::Header => "\x1B[34m▓\x1B[m",
+			Kind::Data => "\x1B[32m█\x1B[m",
+			Kind::Padding => "\x1B[33m▒\x1B[m",
+			Kind::Gap => "\x1B[31m░\x1B[m",
+		}
+	}
+
+	fn color(self) -> &'static str {
+		match self {
+			Kind::Header => "#3465a4",
+			Kind::Data => "#4e9a06",
+			Kind::Padding => "#c4a000",
+			Kind::Gap => "#cc0000",
+		}
+	}
+
+	fn label(self) -> &'static str {
+		match self {
+			Kind::Header => "offset table",
+			Kind::Data => "data",
+			Kind::Padding => "padding",
+			Kind::Gap => "gaps",
+		}
+	}
+}
+
+/// A contiguous part of a .dat file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+	pub start: usize,
+	pub end: usize,
+	pub kind: Kind,
+	/// The entry the region belongs to, for data and padding.
+	pub id: Option<usize>,
+}
+
+pub fn run(cmd: &Command) -> eyre::Result<()> {
+	for (idx, dir_file) in cmd.dir_file.iter().enumerate() {
+		if cmd.dir_file.len() != 1 {
+			println!("{dir_file}:");
+		}
+
+		emit(map(cmd, dir_file));
+
+		if idx + 1 != cmd.dir_file.len() {
+			println!();
+		}
+	}
+	Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(path=%dir_file))]
+fn map(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
+	let _lock = crate::util::lock(dir_file, false, cmd.wait)?;
+	let dir = dirdat::read_dir(&std::fs::read(dir_file)?)?;
+	let dat = crate::util::mmap(&dir_file.with_extension("dat"))?;
+	let dat_len = dat.len();
+	if let Ok(table) = dirdat::read_dat(&dat) {
+		crate::util::check_pair(dir_file, &dir, &table, dat_len, false)?;
+	}
+	let regions = regions(&dir, dat_len);
+
+	let width = cmd.width
+		.or_else(|| term_size::dimensions_stdout().map(|a| a.0))
+		.unwrap_or(80)
+		.max(1);
+	let cells = width * cmd.lines.max(1);
+	let mut bar = String::new();
+	let mut r = 0;
+	for i in 0..cells {
+		if i != 0 && i % width == 0 {
+			bar.push('\n');
+		}
+		let start = dat_len * i / cells;
+		let end = dat_len * (i + 1) / cells;
+		// Which kind covers the most of this cell
+		let mut amount = [0; Kind::ALL.len()];
+		while r < regions.len() && regions[r].end <= start {
+			r += 1;
+		}
+		for region in regions[r..].iter().take_while(|a| a.start < end) {
+			amount[region.kind as usize] += region.end.min(end) - region.start.max(start);
+		}
+		match (0..amount.len()).filter(|&k| amount[k] > 0).max_by_key(|&k| amount[k]) {
+			Some(k) => bar.push_str(Kind::ALL[k].glyph()),
+			None => bar.push(' '),
+		}
+	}
+	println!("{bar}");
+
+	let mut totals = [0; Kind::ALL.len()];
+	for region in &regions {
+		totals[region.kind as usize] += region.end - region.start;
+	}
+	let size = |n| format_bytes(n, cmd.binary, cmd.bytes);
+	let legend = Kind::ALL.iter()
+		.map(|k| format!("{} {} {}", k.glyph(), k.label(), size(totals[*k as usize])))
+		.collect::<Vec<_>>()
+		.join("  ");
+	println!("{legend}");
+	println!(
+		"reclaimable: {} with rebuild, {} with rebuild --pack",
+		size(totals[Kind::Gap as usize]),
+		size(totals[Kind::Gap as usize] + totals[Kind::Padding as usize]),
+	);
+
+	if let Some(svg) = &cmd.svg {
+		let svg = crate::util::output(Some(svg), dir_file, "svg", cmd.dir_file.len())?;
+		std::fs::write(&svg, to_svg(cmd, &dir, &regions, dat_len))?;
+		tracing::info!("wrote {svg}");
+	}
+
+	Ok(())
+}
+
+/// Splits a .dat file into regions, ordered by offset.
+///
+/// Data that overlaps an earlier entry's is only counted once.
+pub fn regions(dir: &[DirEntry], dat_len: usize) -> Vec<Region> {
+	let mut regions = Vec::new();
+	let header = (16 + 4 * (dir.len() + 1)).min(dat_len);
+	regions.push(Region { start: 0, end: header, kind: Kind::Header, id: None });
+
+	let mut entries = dir.iter()
+		.enumerate()
+		.filter(|(_, e)| e.offset != 0)
+		.collect::<Vec<_>>();
+	entries.sort_by_key(|(_, e)| e.offset);
+
+	let mut pos = header;
+	let mut push = |start: usize, end: usize, kind, id| {
+		let start = start.max(pos).min(dat_len);
+		let end = end.min(dat_len);
+		if start > pos {
+			regions.push(Region { start: pos, end: start, kind: Kind::Gap, id: None });
+		}
+		if end > start {
+			regions.push(Region { start, end, kind, id });
+		}
+		pos = pos.max(end).max(start);
+	};
+	for (id, e) in entries {
+		push(e.offset, e.offset + e.size, Kind::Data, Some(id));
+		push(e.offset + e.size, e.offset + e.reserved_size, Kind::Padding, Some(id));
+	}
+	push(dat_len, dat_len, Kind::Gap, None);
+
+	regions
+}
+
+fn to_svg(cmd: &Command, dir: &[DirEntry], regions: &[Region], dat_len: usize) -> String {
+	const WIDTH: f64 = 1024.;
+	const HEIGHT: f64 = 48.;
+	let scale = WIDTH / dat_len.max(1) as f64;
+
+	let mut s = String::new();
+	writeln!(s, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}">"#).unwrap();
+	for region in regions {
+		let title = match region.id {
+			Some(id) => format!(
+				"{id:04X} {} ({}): {}..{}",
+				escape_xml(&dir[id].name.decode(cmd.encoding)),
+				region.kind.label(),
+				region.start,
+				region.end,
+			),
+			None => format!("{}: {}..{}", region.kind.label(), region.start, region.end),
+		};
+		writeln!(
+			s,
+			r#"<rect x="{:.3}" y="0" width="{:.3}" height="{HEIGHT}" fill="{}"><title>{title}</title></rect>"#,
+			region.start as f64 * scale,
+			(region.end - region.start) as f64 * scale,
+			region.kind.color(),
+		).unwrap();
+	}
+	writeln!(s, "</svg>").unwrap();
+	s
+}
+
+fn escape_xml(s: &str) -> String {
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
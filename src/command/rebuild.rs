@@ -5,6 +5,7 @@ use clap::ValueHint;
 
 use eyre_span::emit;
 use crate::dirdat::{self, DirEntry, Name};
+use crate::packing::{Packing, SortKey};
 
 #[derive(Debug, Clone, clap::Args)]
 #[command(arg_required_else_help = true)]
@@ -20,6 +21,24 @@ pub struct Command {
 	#[clap(short, long)]
 	pack: bool,
 
+	#[clap(flatten)]
+	packing: Packing,
+
+	/// Rebuild the archive even if the .dat does not seem to belong with the .dir
+	#[clap(long)]
+	allow_mismatch: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	///
+	/// Reading an archive does not create its .lock file, so this only has an effect once a command
+	/// that modifies the archive has left one next to it.
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
 	/// Where to place the resulting .dir.
 	///
 	/// .dat file will be placed next to the .dir.
@@ -40,9 +59,6 @@ pub fn run(cmd: &Command) -> eyre::Result<()> {
 
 #[tracing::instrument(skip_all, fields(path=%dir_file, out))]
 fn rebuild(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
-	let mut dir = dirdat::read_dir(&std::fs::read(dir_file)?)?;
-	let dat = crate::util::mmap(&dir_file.with_extension("dat"))?;
-
 	let out_dir = match &cmd.output {
 		None => dir_file.to_path_buf(),
 		Some(f) if f.is_dir() || cmd.dir_file.len() > 1 => f.join(dir_file.file_name().unwrap()),
@@ -51,7 +67,19 @@ fn rebuild(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
 
 	tracing::Span::current().record("out", tracing::field::display(&out_dir));
 
+	let in_place = same_file(&out_dir, dir_file);
+	let _lock = crate::util::lock(dir_file, in_place, cmd.wait)?;
+	let mut dir = dirdat::read_dir(&std::fs::read(dir_file)?)?;
+	let dat = crate::util::mmap(&dir_file.with_extension("dat"))?;
+	let table = dirdat::read_dat(&dat).map_err(|_| eyre::eyre!("invalid dat file"))?;
+	crate::util::check_pair(dir_file, &dir, &table, dat.len(), !cmd.allow_mismatch)?;
+
 	std::fs::create_dir_all(out_dir.parent().unwrap())?;
+	let _out_lock = if in_place {
+		None
+	} else {
+		Some(crate::util::lock(&out_dir, true, cmd.wait)?)
+	};
 
 	let expected_size = cmd.reserve.unwrap_or(dir.len());
 	while dir.len() < expected_size {
@@ -71,32 +99,63 @@ fn rebuild(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<()> {
 		out_dat.write_all(&u32::to_le_bytes(0))?;
 	}
 
-	for (id, ent) in dir.iter_mut().enumerate() {
-		let _span = tracing::debug_span!("rebuild_file", id=%format_args!("{id:04X}"), name=%ent.name).entered();
+	for ent in &mut dir {
 		if ent.name == Name::default() {
 			*ent = DirEntry::default();
-		} else {
-			if cmd.pack {
-				ent.reserved_size = ent.size;
-			}
-			let size = ent.size.max(ent.reserved_size);
-			let Some(data) = dat.get(ent.offset..ent.offset+size) else {
-				eyre::bail!("invalid range")
-			};
-			let pos = out_dat.seek(SeekFrom::End(0))?;
-			out_dat.write_all(data)?;
-			let pos2 = out_dat.seek(SeekFrom::End(0))?;
-			out_dat.seek(SeekFrom::Start(16 + 4 * id as u64))?;
-			out_dat.write_all(&u32::to_le_bytes(pos as u32))?;
-			out_dat.write_all(&u32::to_le_bytes(pos2 as u32))?;
-			ent.offset = pos as usize;
 		}
 	}
 
+	let names = dir.iter().map(|e| e.name.decode(cmd.encoding)).collect::<Vec<_>>();
+	let mut order = dir.iter().enumerate()
+		.filter(|(_, e)| e.name != Name::default())
+		.map(|(id, e)| SortKey { id, name: &names[id], offset: Some(e.offset) })
+		.collect::<Vec<_>>();
+	cmd.packing.order(&mut order);
+
+	let mut pos = out_dat.seek(SeekFrom::End(0))?;
+	for SortKey { id, name, .. } in order {
+		let ent = &mut dir[id];
+		let _span = tracing::debug_span!("rebuild_file", id=%format_args!("{id:04X}"), name=%ent.name).entered();
+		if cmd.pack {
+			ent.reserved_size = ent.size;
+		}
+		let data = &dat[ent.alloc_range(dat.len())?];
+		if ent.timestamp != 0 {
+			ent.reserved_size = cmd.packing.reserve(name, ent.size, ent.reserved_size);
+		}
+		let start = cmd.packing.align(pos);
+		let end = start + ent.size.max(ent.reserved_size) as u64;
+		out_dat.write_all(&vec![0; (start - pos) as usize])?;
+		out_dat.write_all(data)?;
+		out_dat.write_all(&vec![0; (end - start) as usize - data.len()])?;
+		dirdat::to_u32(ent.name, "end offset", end)?;
+		ent.offset = start as usize;
+		pos = end;
+	}
+
+	out_dat.seek(SeekFrom::Start(16))?;
+	out_dat.write_all(&dirdat::write_dat_table(&dir)?)?;
+
+	let dir_data = dirdat::write_dir(&dir)?;
 	std::fs::rename(out_dir.with_extension("dat.tmp"), out_dir.with_extension("dat"))?;
-	std::fs::write(&out_dir, dirdat::write_dir(&dir))?;
+	std::fs::write(&out_dir, dir_data)?;
 	
 	tracing::info!("rebuilt");
 
 	Ok(())
 }
+
+/// Whether two paths name the same file, however they are spelled. `a` does not need to exist.
+fn same_file(a: &Utf8Path, b: &Utf8Path) -> bool {
+	let canonical = |path: &Utf8Path| match path.canonicalize_utf8() {
+		Ok(path) => Some(path),
+		Err(_) => {
+			let parent = path.parent().filter(|p| !p.as_str().is_empty()).unwrap_or(Utf8Path::new("."));
+			Some(parent.canonicalize_utf8().ok()?.join(path.file_name()?))
+		}
+	};
+	match (canonical(a), canonical(b)) {
+		(Some(a), Some(b)) => a == b,
+		_ => a == b,
+	}
+}
@@ -0,0 +1,110 @@
+use std::fs::File;
+
+use camino::Utf8PathBuf;
+use clap::ValueHint;
+
+use eyre_span::emit;
+use falcompress::bzip;
+use crate::compression::Compression;
+use crate::dirdat::{self, DirEntry};
+
+#[derive(Debug, Clone, clap::Args)]
+#[command(arg_required_else_help = true)]
+/// Changes the compression of files in an archive.
+///
+/// Files are stored in place if they fit in their existing allocation, and appended otherwise,
+/// in the same way as `factoria add`.
+pub struct Command {
+	/// Compression to use
+	#[clap(short, long, value_enum)]
+	mode: Compression,
+
+	/// With --mode=auto, only compress files if it makes them at least this many percent smaller
+	#[clap(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+	min_savings: u8,
+
+	/// Check that compressed data decompresses correctly before writing it
+	#[clap(long)]
+	verify: bool,
+
+	/// Filter which files to recompress
+	#[clap(short, long, value_parser = crate::util::glob_parser())]
+	glob: Vec<globset::Glob>,
+
+	/// Modify the archive even if the .dat does not seem to belong with the .dir
+	#[clap(long)]
+	allow_mismatch: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
+	/// .dir file to recompress
+	#[clap(value_hint = ValueHint::FilePath, required = true)]
+	dir_file: Utf8PathBuf,
+}
+
+#[tracing::instrument(skip_all, fields(path=%cmd.dir_file))]
+pub fn run(cmd: &Command) -> eyre::Result<()> {
+	let _lock = crate::util::lock(&cmd.dir_file, true, cmd.wait)?;
+	let mut dir = dirdat::read_dir(&std::fs::read(&cmd.dir_file)?)?;
+
+	let mut dat = File::options()
+		.read(true)
+		.write(true)
+		.open(cmd.dir_file.with_extension("dat"))?;
+
+	let table = crate::util::read_dat_table(&mut dat)?;
+	crate::util::check_pair(&cmd.dir_file, &dir, &table, dat.metadata()?.len() as usize, !cmd.allow_mismatch)?;
+
+	let mut globset = globset::GlobSetBuilder::new();
+	for glob in &cmd.glob {
+		globset.add(glob.clone());
+	}
+	let globset = globset.build()?;
+
+	for id in 0..dir.len() {
+		let e = &dir[id];
+		if e.name == dirdat::Name::default() || e.timestamp == 0 {
+			continue
+		}
+		if !globset.is_empty() && !globset.is_match(e.name.decode(cmd.encoding)) {
+			continue
+		}
+		emit(recompress(cmd, &mut dir[id], &mut dat, id));
+	}
+
+	emit(super::add::trim(&mut dat, &mut dir));
+	super::add::write_table(&mut dat, &dir)?;
+
+	std::fs::write(&cmd.dir_file, dirdat::write_dir(&dir)?)?;
+
+	Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(name=%ent.name))]
+fn recompress(cmd: &Command, ent: &mut DirEntry, dat: &mut File, id: usize) -> eyre::Result<()> {
+	let existing = super::add::read_existing(dat, ent, id)?;
+	let current = bzip::compression_info_ed6(&existing).map(|a| a.1.unwrap_or_default());
+
+	if Compression::from(current) == cmd.mode {
+		tracing::debug!("already using that compression");
+		return Ok(())
+	}
+
+	let data = match current {
+		Some(_) => bzip::decompress_ed6_from_slice(&existing)?,
+		None => existing,
+	};
+	let data = cmd.mode.compress(data, cmd.min_savings, cmd.verify)?;
+
+	let old_size = ent.size;
+	super::add::store(dat, ent, id, true, data, None)?;
+	tracing::info!("{} → {} bytes ({:+})", old_size, ent.size, ent.size as i64 - old_size as i64);
+
+	Ok(())
+}
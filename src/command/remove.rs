@@ -9,7 +9,7 @@ use crate::dirdat::{self, DirEntry, Name};
 /// Deletes one or more files from an archive file.
 ///
 /// Note however that while the data is zeroed out, the space it previously occupied
-/// remains. Use `factoria rebuild` to remove this.
+/// remains, unless it is at the end of the file. Use `factoria rebuild` to remove this.
 ///
 /// Falcom's archives have many files that are nothing but a filename. By default,
 /// this command replicates this behavior: the -f flag overrides this behavior and
@@ -19,6 +19,18 @@ pub struct Command {
 	#[clap(short, long)]
 	force: bool,
 
+	/// Modify the archive even if the .dat does not seem to belong with the .dir
+	#[clap(long)]
+	allow_mismatch: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
 	/// .dir file to insert into
 	#[clap(value_hint = ValueHint::FilePath, required = true)]
 	dir_file: Utf8PathBuf,
@@ -30,35 +42,52 @@ pub struct Command {
 
 #[tracing::instrument(skip_all, fields(path=%cmd.dir_file))]
 pub fn run(cmd: &Command) -> eyre::Result<()> {
+	let _lock = crate::util::lock(&cmd.dir_file, true, cmd.wait)?;
 	let mut dir = dirdat::read_dir(&std::fs::read(&cmd.dir_file)?)?;
 	let mut dat = crate::util::mmap_mut(&cmd.dir_file.with_extension("dat"))?;
 
-	eyre::ensure!(dat[0..8] == *b"LB DAT\x1A\0", "invalid dat file");
+	let table = dirdat::read_dat(&dat).map_err(|_| eyre::eyre!("invalid dat file"))?;
+	crate::util::check_pair(&cmd.dir_file, &dir, &table, dat.len(), !cmd.allow_mismatch)?;
 
 	for file in &cmd.file {
 		emit(remove(cmd, &mut dir, &mut dat, file));
 	}
 
-	std::fs::write(&cmd.dir_file, dirdat::write_dir(&dir))?;
+	drop(dat);
+	let mut dat = std::fs::File::options()
+		.read(true)
+		.write(true)
+		.open(cmd.dir_file.with_extension("dat"))?;
+	emit(super::add::trim(&mut dat, &mut dir));
+	super::add::write_table(&mut dat, &dir)?;
+
+	std::fs::write(&cmd.dir_file, dirdat::write_dir(&dir)?)?;
 
 	Ok(())
 }
 
 #[tracing::instrument(skip_all, fields(file=%file))]
 fn remove(cmd: &Command, dir: &mut [DirEntry], dat: &mut [u8], file: &str) -> eyre::Result<()> {
-	let name = Name::try_from(file)?;
+	let name = Name::encode(file, cmd.encoding)?;
+	remove_entry(dir, dat, name, cmd.force)
+}
 
+/// Removes the entry with the given name, zeroing out its data in the .dat file.
+pub fn remove_entry(dir: &mut [DirEntry], dat: &mut [u8], name: Name, force: bool) -> eyre::Result<()> {
 	let Some(id) = dir.iter().position(|e| e.name == name) else {
 		eyre::bail!("not found in archive");
 	};
 
 	let ent = &mut dir[id];
 	
-	if ent.timestamp == 0 && !cmd.force {
+	if ent.timestamp == 0 && !force {
 		eyre::bail!("file is already soft-deleted (use -f to hard delete)");
 	}
 
-	dat[ent.offset..][..ent.reserved_size.max(ent.size)].fill(0);
+	let range = ent.alloc_range(dat.len())?;
+	let slot = 16+id*4..16+id*4+4;
+	eyre::ensure!(!force || slot.end <= dat.len(), "dat offset table is too short");
+	dat[range].fill(0);
 
 	*ent = DirEntry {
 		name,
@@ -70,9 +99,9 @@ fn remove(cmd: &Command, dir: &mut [DirEntry], dat: &mut [u8], file: &str) -> ey
 		offset: ent.offset,
 	};
 
-	if cmd.force {
+	if force {
 		*ent = DirEntry::default();
-		dat[16+id*4..][..4].fill(0);
+		dat[slot].fill(0);
 	}
 
 	tracing::info!("removed {} at {:04X}", name, id);
@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::time::SystemTime;
+
+use camino::Utf8PathBuf;
+use clap::ValueHint;
+
+use eyre_span::emit;
+use crate::dirdat::{self, DirEntry, Name};
+
+#[derive(Debug, Clone, clap::Args)]
+#[command(arg_required_else_help = true)]
+/// Changes the metadata of files in an archive, without touching their data.
+///
+/// Files can be given either by name or by glob. Increasing the reserved size past the current
+/// allocation moves the file to the end of the dat, in the same way as `factoria add`.
+pub struct Command {
+	/// Modification time, as unix seconds, `YYYY-MM-DD HH:MM:SS`, or `now`
+	#[clap(short, long, value_parser = parse_timestamp)]
+	timestamp: Option<u32>,
+
+	/// Value of the first unknown field
+	#[clap(long)]
+	unknown1: Option<u32>,
+
+	/// Value of the second unknown field
+	#[clap(long)]
+	unknown2: Option<u32>,
+
+	/// Space reserved for the file in the dat; must be at least the file's size
+	#[clap(short, long)]
+	reserve: Option<usize>,
+
+	/// Modify the archive even if the .dat does not seem to belong with the .dir
+	#[clap(long)]
+	allow_mismatch: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
+	/// .dir file to modify
+	#[clap(value_hint = ValueHint::FilePath, required = true)]
+	dir_file: Utf8PathBuf,
+
+	/// Names or globs of files to modify
+	#[clap(required = true)]
+	file: Vec<String>,
+}
+
+#[tracing::instrument(skip_all, fields(path=%cmd.dir_file))]
+pub fn run(cmd: &Command) -> eyre::Result<()> {
+	if cmd.timestamp.is_none() && cmd.unknown1.is_none() && cmd.unknown2.is_none() && cmd.reserve.is_none() {
+		eyre::bail!("nothing to set; use --timestamp, --unknown1, --unknown2, or --reserve");
+	}
+
+	let _lock = crate::util::lock(&cmd.dir_file, true, cmd.wait)?;
+	let mut dir = dirdat::read_dir(&std::fs::read(&cmd.dir_file)?)?;
+
+	let mut dat = File::options()
+		.read(true)
+		.write(true)
+		.open(cmd.dir_file.with_extension("dat"))?;
+
+	let table = crate::util::read_dat_table(&mut dat)?;
+	crate::util::check_pair(&cmd.dir_file, &dir, &table, dat.metadata()?.len() as usize, !cmd.allow_mismatch)?;
+
+	let mut ids = Vec::new();
+	for file in &cmd.file {
+		if let Some(found) = emit(find(cmd, &dir, file)) {
+			ids.extend(found);
+		}
+	}
+	ids.sort();
+	ids.dedup();
+
+	for id in ids {
+		emit(set(cmd, &mut dir[id], &mut dat, id));
+	}
+
+	emit(super::add::trim(&mut dat, &mut dir));
+	super::add::write_table(&mut dat, &dir)?;
+
+	std::fs::write(&cmd.dir_file, dirdat::write_dir(&dir)?)?;
+
+	Ok(())
+}
+
+/// Finds the files matching a name or glob.
+///
+/// Exact names may refer to soft-deleted files, so that the error is more helpful than "not found".
+#[tracing::instrument(skip_all, fields(file=%file))]
+fn find(cmd: &Command, dir: &[DirEntry], file: &str) -> eyre::Result<Vec<usize>> {
+	if let Ok(name) = Name::encode(file, cmd.encoding) {
+		if let Some(id) = dir.iter().position(|e| e.name == name) {
+			eyre::ensure!(dir[id].timestamp != 0, "file is soft-deleted");
+			return Ok(vec![id])
+		}
+	}
+
+	let glob = crate::util::glob(file)?.compile_matcher();
+	let ids = dir.iter()
+		.enumerate()
+		.filter(|(_, e)| e.name != Name::default() && e.timestamp != 0)
+		.filter(|(_, e)| glob.is_match(e.name.decode(cmd.encoding)))
+		.map(|(id, _)| id)
+		.collect::<Vec<_>>();
+	eyre::ensure!(!ids.is_empty(), "not found in archive");
+	Ok(ids)
+}
+
+#[tracing::instrument(skip_all, fields(name=%ent.name))]
+fn set(cmd: &Command, ent: &mut DirEntry, dat: &mut File, id: usize) -> eyre::Result<()> {
+	if let Some(reserve) = cmd.reserve {
+		eyre::ensure!(reserve >= ent.size, "cannot reserve {reserve} bytes for a file of {} bytes", ent.size);
+		dirdat::to_u32(ent.name, "reserved size", reserve as u64)?;
+		let alloc = ent.size.max(ent.reserved_size);
+		if reserve > alloc {
+			let data = super::add::read_existing(dat, ent, id)?;
+			super::add::store(dat, ent, id, true, data, Some(reserve))?;
+		} else if reserve < alloc && ent.offset != 0 {
+			// Zero the space given up, so that it can be reclaimed if it is at the end of the dat
+			let range = ent.alloc_range(dat.metadata()?.len() as usize)?;
+			dat.seek(SeekFrom::Start((range.start + reserve) as u64))?;
+			dat.write_all(&vec![0; range.len() - reserve])?;
+		}
+		ent.reserved_size = reserve;
+	}
+	if let Some(timestamp) = cmd.timestamp {
+		ent.timestamp = timestamp;
+	}
+	if let Some(unk1) = cmd.unknown1 {
+		ent.unk1 = unk1;
+	}
+	if let Some(unk2) = cmd.unknown2 {
+		ent.unk2 = unk2 as usize;
+	}
+
+	tracing::info!("updated {} at {:04X}", ent.name, id);
+
+	Ok(())
+}
+
+fn parse_timestamp(s: &str) -> Result<u32, String> {
+	let secs = if s == "now" {
+		SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.map_err(|e| e.to_string())?
+			.as_secs() as i64
+	} else if let Ok(secs) = s.parse::<i64>() {
+		secs
+	} else {
+		chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+			.map_err(|_| "expected unix seconds, YYYY-MM-DD HH:MM:SS, or now".to_owned())?
+			.timestamp()
+	};
+	match u32::try_from(secs) {
+		Ok(0) => Err("a timestamp of 0 marks the file as deleted".to_owned()),
+		Ok(secs) => Ok(secs),
+		Err(_) => Err(format!("{s} does not fit in 32 bits")),
+	}
+}
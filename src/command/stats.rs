@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+use camino::{Utf8PathBuf, Utf8Path};
+use clap::ValueHint;
+
+use eyre_span::emit;
+use crate::dirdat;
+use crate::grid::{Grid, Cell, Orientation};
+use super::list::format_bytes;
+use super::map::Kind;
+
+#[derive(Debug, Clone, clap::Args)]
+#[command(arg_required_else_help = true)]
+/// Summarizes the contents of archives.
+///
+/// Wasted space is split into gaps, which are not claimed by any file and can be removed with
+/// `factoria rebuild`, and padding, which is reserved for files to grow into.
+pub struct Command {
+	/// Output as json
+	#[clap(short, long)]
+	json: bool,
+
+	/// Use binary prefixes instead of SI for file sizes
+	#[clap(short, long)]
+	binary: bool,
+	/// Display raw number of bytes for file sizes
+	#[clap(short='B', long, overrides_with("binary"))]
+	bytes: bool,
+
+	/// If the archive is in use by another process, wait for it instead of failing
+	///
+	/// Reading an archive does not create its .lock file, so this only has an effect once a command
+	/// that modifies the archive has left one next to it.
+	#[clap(long)]
+	wait: bool,
+
+	/// Character encoding of file names in the archive
+	#[clap(long, value_enum, default_value = "sjis")]
+	encoding: dirdat::Encoding,
+
+	/// The .dir file(s) to inspect.
+	#[clap(value_hint = ValueHint::FilePath, required = true)]
+	dir_file: Vec<Utf8PathBuf>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+struct Stats {
+	/// Number of slots in the index
+	capacity: usize,
+	/// Files with data
+	files: usize,
+	placeholders: usize,
+	/// Files that have a name, but no data
+	deleted: usize,
+	stored_size: usize,
+	decompressed_size: usize,
+	dat_size: usize,
+	gap_size: usize,
+	padding_size: usize,
+	extensions: BTreeMap<String, ExtStats>,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+struct ExtStats {
+	files: usize,
+	compressed: usize,
+	stored_size: usize,
+	decompressed_size: usize,
+}
+
+pub fn run(cmd: &Command) -> eyre::Result<()> {
+	if cmd.json {
+		let mut json = serde_json::Map::new();
+		for dir_file in &cmd.dir_file {
+			if let Some(stats) = emit(stats(cmd, dir_file)) {
+				json.insert(dir_file.to_string(), serde_json::to_value(stats)?);
+			}
+		}
+		println!("{}", serde_json::to_string_pretty(&json)?);
+		return Ok(())
+	}
+
+	for (idx, dir_file) in cmd.dir_file.iter().enumerate() {
+		if cmd.dir_file.len() != 1 {
+			println!("{dir_file}:");
+		}
+
+		if let Some(stats) = emit(stats(cmd, dir_file)) {
+			print_stats(cmd, &stats);
+		}
+
+		if idx + 1 != cmd.dir_file.len() {
+			println!();
+		}
+	}
+	Ok(())
+}
+
+#[tracing::instrument(skip_all, fields(path=%dir_file))]
+fn stats(cmd: &Command, dir_file: &Utf8Path) -> eyre::Result<Stats> {
+	let entries = super::list::read_entries(dir_file, true, cmd.wait)?;
+	let dat_size = std::fs::metadata(dir_file.with_extension("dat"))?.len() as usize;
+
+	let mut stats = Stats {
+		capacity: entries.len(),
+		dat_size,
+		..Stats::default()
+	};
+
+	for e in &entries {
+		if e.name == dirdat::Name::default() {
+			stats.placeholders += 1;
+			continue
+		}
+		if e.timestamp == 0 {
+			stats.deleted += 1;
+			continue
+		}
+
+		let decompressed_size = e.decompressed_size.unwrap_or(e.size);
+		stats.files += 1;
+		stats.stored_size += e.size;
+		stats.decompressed_size += decompressed_size;
+		stats.padding_size += e.reserved_size.saturating_sub(e.size);
+
+		let name = e.name.decode(cmd.encoding);
+		let ext = name.split_once('.').map_or("", |a| a.1);
+		let ext = stats.extensions.entry(ext.to_owned()).or_default();
+		ext.files += 1;
+		ext.compressed += usize::from(e.decompressed_size.is_some());
+		ext.stored_size += e.size;
+		ext.decompressed_size += decompressed_size;
+	}
+
+	let dir = entries.iter().map(|e| e.dirent.clone()).collect::<Vec<_>>();
+	stats.gap_size = super::map::regions(&dir, dat_size).iter()
+		.filter(|r| r.kind == Kind::Gap)
+		.map(|r| r.end - r.start)
+		.sum();
+
+	Ok(stats)
+}
+
+fn print_stats(cmd: &Command, stats: &Stats) {
+	let size = |n| format_bytes(n, cmd.binary, cmd.bytes);
+	let ratio = |stored: usize, decompressed: usize| if decompressed == 0 {
+		"-".to_owned()
+	} else {
+		format!("{:.0}%", stored as f64 / decompressed as f64 * 100.)
+	};
+
+	let mut cells = Vec::new();
+	let mut row = |label: &str, value: String, extra: String| {
+		cells.push(Cell::left(label.to_owned()));
+		cells.push(Cell::right(value));
+		cells.push(Cell::left(extra));
+	};
+	row("files", stats.files.to_string(), format!(
+		"of {} slots, {} placeholders, {} deleted",
+		stats.capacity, stats.placeholders, stats.deleted,
+	));
+	row("stored", size(stats.stored_size), ratio(stats.stored_size, stats.decompressed_size));
+	row("decompressed", size(stats.decompressed_size), String::new());
+	row("dat", size(stats.dat_size), String::new());
+	row("gaps", size(stats.gap_size), String::new());
+	row("padding", size(stats.padding_size), String::new());
+	print!("{}", Grid::best_fit(0, Orientation::Horizontal, 3, &cells, " "));
+
+	if !stats.extensions.is_empty() {
+		println!();
+		let mut cells = vec![
+			Cell::left("ext".into()),
+			Cell::right("files".into()),
+			Cell::right("compressed".into()),
+			Cell::right("stored".into()),
+			Cell::right("decompressed".into()),
+			Cell::right("ratio".into()),
+		];
+		for (ext, e) in &stats.extensions {
+			cells.push(Cell::left(ext.clone()));
+			cells.push(Cell::right(e.files.to_string()));
+			cells.push(Cell::right(e.compressed.to_string()));
+			cells.push(Cell::right(size(e.stored_size)));
+			cells.push(Cell::right(size(e.decompressed_size)));
+			cells.push(Cell::right(ratio(e.stored_size, e.decompressed_size)));
+		}
+		print!("{}", Grid::best_fit(0, Orientation::Horizontal, 6, &cells, " "));
+	}
+}
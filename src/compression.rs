@@ -0,0 +1,62 @@
+use falcompress::bzip;
+
+/// How to compress a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Compression {
+	#[default]
+	None,
+	#[value(name = "1")]
+	Mode1,
+	#[value(name = "2")]
+	Mode2,
+	/// Whichever of the above gives the smallest result
+	Auto,
+}
+
+impl From<Option<bzip::CompressMode>> for Compression {
+	fn from(mode: Option<bzip::CompressMode>) -> Self {
+		match mode {
+			None => Compression::None,
+			Some(bzip::CompressMode::Mode1) => Compression::Mode1,
+			Some(bzip::CompressMode::Mode2) => Compression::Mode2,
+		}
+	}
+}
+
+impl Compression {
+	/// Compresses `data`.
+	///
+	/// For `Auto`, all modes are tried in parallel, and compression is only used if it saves at least
+	/// `min_savings` percent. Ties go to no compression, then to mode 1.
+	///
+	/// If `verify` is set, or in debug builds, the result is decompressed again and compared to the input.
+	pub fn compress(self, data: Vec<u8>, min_savings: u8, verify: bool) -> eyre::Result<Vec<u8>> {
+		let compressed = match self {
+			Compression::None => return Ok(data),
+			Compression::Mode1 => bzip::compress_ed6_to_vec(&data, bzip::CompressMode::Mode1),
+			Compression::Mode2 => bzip::compress_ed6_to_vec(&data, bzip::CompressMode::Mode2),
+			Compression::Auto => {
+				let (mode1, mode2) = rayon::join(
+					|| bzip::compress_ed6_to_vec(&data, bzip::CompressMode::Mode1),
+					|| bzip::compress_ed6_to_vec(&data, bzip::CompressMode::Mode2),
+				);
+				let (mode, best) = if mode2.len() < mode1.len() { (2, mode2) } else { (1, mode1) };
+				if best.len() * 100 <= data.len() * (100 - min_savings.min(100) as usize) && best.len() < data.len() {
+					tracing::debug!("using compression mode {mode}, {} → {} bytes", data.len(), best.len());
+					best
+				} else {
+					tracing::debug!("using no compression, {} bytes", data.len());
+					return Ok(data)
+				}
+			}
+		};
+
+		if verify || cfg!(debug_assertions) {
+			let roundtrip = bzip::decompress_ed6_from_slice(&compressed)?;
+			eyre::ensure!(roundtrip == data, "compressed data does not decompress to the original");
+			tracing::trace!("verified compression");
+		}
+
+		Ok(compressed)
+	}
+}
@@ -1,5 +1,7 @@
 //! Utilities for reading ED6 PC's LB DIR files.
 
+use std::ops::Range;
+
 use gospel::read::{Reader, Le as _};
 use gospel::write::{Writer, Le as _};
 
@@ -7,7 +9,9 @@ use gospel::write::{Writer, Le as _};
 ///
 /// Internally this is represented as a `[u8; 12]` usually denoting a 8.3 uppercase shift-jis encoded name, but
 /// the public interface treats it like a more normal looking string.
-#[derive(Clone, Copy, PartialEq, Eq)]
+///
+/// The `Display` and `TryFrom` impls use shift-jis; use [`Name::decode`] and [`Name::encode`] for other encodings.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Name([u8; 12]);
 
 impl std::ops::Deref for Name {
@@ -32,13 +36,7 @@ impl std::fmt::Debug for Name {
 
 impl std::fmt::Display for Name {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		let name = falcom_sjis::decode_lossy(&self.0);
-		let name = name.to_lowercase();
-		if let Some((name, ext)) = name.split_once('.') {
-			write!(f, "{}.{}", name.trim_end_matches(' '), ext.trim_end_matches(' '))
-		} else {
-			f.write_str(name.trim_end_matches(' '))
-		}
+		f.write_str(&self.decode(Encoding::Sjis))
 	}
 }
 
@@ -54,11 +52,97 @@ impl TryFrom<&str> for Name {
 	type Error = NameError;
 
 	fn try_from(name: &str) -> Result<Self, Self::Error> {
+		Name::encode(name, Encoding::Sjis)
+	}
+}
+
+impl Name {
+	/// Converts the name to a lowercase string with the padding removed.
+	///
+	/// Bytes that are not valid in the encoding are replaced with U+FFFD.
+	pub fn decode(&self, encoding: Encoding) -> String {
+		let name = encoding.lowercase(&encoding.decode(&self.0));
+		if let Some((name, ext)) = name.split_once('.') {
+			format!("{}.{}", name.trim_end_matches(' '), ext.trim_end_matches(' '))
+		} else {
+			name.trim_end_matches(' ').to_owned()
+		}
+	}
+
+	/// Converts the name to a string that [`Name::encode`] turns back into the exact same name.
+	///
+	/// This is the same as [`Name::decode`] whenever that round-trips. Otherwise, for example with
+	/// lowercase letters, unusual padding, or bytes that are invalid in the encoding, all twelve
+	/// bytes are written out, using `\xNN` for spaces and any byte that cannot be written as is,
+	/// and `\\` for backslashes. At least one byte is always escaped, which is how `encode`
+	/// tells this form apart from normal names.
+	pub fn to_escaped(&self, encoding: Encoding) -> String {
+		let name = self.decode(encoding);
+		if Name::encode(&name, encoding) == Ok(*self) {
+			return name;
+		}
+		self.escape(encoding)
+	}
+
+	/// The escaped part of [`Name::to_escaped`], which is used even when the plain name would round-trip.
+	fn escape(&self, encoding: Encoding) -> String {
+		let mut out = String::new();
+		let mut escaped = false;
+		let mut bytes = &self.0[..];
+		'outer: while let Some(&b) = bytes.first() {
+			for n in (1..=2).rev() {
+				let Some(chunk) = bytes.get(..n) else { continue };
+				let s = encoding.decode(chunk);
+				let mut chars = s.chars();
+				if let (Some(c), None) = (chars.next(), chars.next()) {
+					let printable = !c.is_control() && !matches!(c, ' ' | '\\' | '\u{FFFD}');
+					if printable && encoding.encode(&s).as_deref() == Some(chunk) {
+						out.push(c);
+						bytes = &bytes[n..];
+						continue 'outer;
+					}
+				}
+			}
+			if b == b'\\' {
+				out.push_str("\\\\");
+			} else {
+				out.push_str(&format!("\\x{b:02X}"));
+			}
+			escaped = true;
+			bytes = &bytes[1..];
+		}
+
+		if !escaped {
+			let last = out.pop().unwrap();
+			for b in encoding.encode(&last.to_string()).unwrap() {
+				out.push_str(&format!("\\x{b:02X}"));
+			}
+		}
+		out
+	}
+
+	/// Converts a file name into 8.3 form. Any leading directories are ignored.
+	///
+	/// Names that are exactly what [`Name::to_escaped`] produces are instead taken literally.
+	/// Anything else containing backslashes, even if it looks like an escape, is a normal path.
+	pub fn encode(name: &str, encoding: Encoding) -> Result<Name, NameError> {
+		if let Some(Ok(bytes)) = unescape(name, encoding) {
+			if let Ok(bytes) = <[u8; 12]>::try_from(bytes) {
+				let escaped = Name(bytes);
+				if escaped.escape(encoding) == name && Name::encode_plain(&escaped.decode(encoding), encoding) != Ok(escaped) {
+					return Ok(escaped);
+				}
+			}
+		}
+		Name::encode_plain(name, encoding)
+	}
+
+	fn encode_plain(name: &str, encoding: Encoding) -> Result<Name, NameError> {
 		let (_, name) = name.rsplit_once(['/', '\\']).unwrap_or(("", name));
-		let name = name.to_uppercase();
+		let name = encoding.uppercase(name);
 		let (name, ext) = name.split_once('.').unwrap_or((&name, ""));
-		let name = falcom_sjis::encode(name).map_err(|_| NameError)?;
-		let ext = falcom_sjis::encode(ext).map_err(|_| NameError)?;
+		let name = encoding.encode(name).ok_or(NameError)?;
+		let ext = encoding.encode(ext).ok_or(NameError)?;
 		if name.len() > 8 || ext.len() > 3 { return Err(NameError); }
 		let mut o = *b"        .   ";
 		o[..name.len()].copy_from_slice(&name);
@@ -67,6 +151,89 @@ impl TryFrom<&str> for Name {
 	}
 }
 
+/// Parses the escaped form from [`Name::to_escaped`].
+///
+/// Returns `None` if the string is not in that form, which is when it has no backslashes,
+/// or has any that are not part of a `\xNN` or `\\` escape.
+/// [`Name::encode`] checks that the result is canonical, since this accepts more than that.
+fn unescape(name: &str, encoding: Encoding) -> Option<Result<Vec<u8>, NameError>> {
+	let mut out = Vec::new();
+	let mut valid = true;
+	let mut rest = name;
+	while let Some((text, tail)) = rest.split_once('\\') {
+		match encoding.encode(text) {
+			Some(bytes) => out.extend(bytes),
+			None => valid = false,
+		}
+		if let Some(tail) = tail.strip_prefix('\\') {
+			out.push(b'\\');
+			rest = tail;
+		} else {
+			let hex = tail.strip_prefix('x')?.get(..2)?;
+			if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+				return None;
+			}
+			out.push(u8::from_str_radix(hex, 16).unwrap());
+			rest = &tail[3..];
+		}
+	}
+	if rest.len() == name.len() {
+		return None;
+	}
+	match encoding.encode(rest) {
+		Some(bytes) => out.extend(bytes),
+		None => valid = false,
+	}
+	Some(if valid { Ok(out) } else { Err(NameError) })
+}
+
+/// The character encoding used for names in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Encoding {
+	/// Shift-JIS, as used in Falcom's releases
+	#[default]
+	Sjis,
+	/// GBK, as used in Chinese releases
+	Gbk,
+	/// Each byte is one character, like Latin-1
+	Raw,
+}
+
+impl Encoding {
+	fn decode(self, bytes: &[u8]) -> String {
+		match self {
+			Encoding::Sjis => falcom_sjis::decode_lossy(bytes).to_string(),
+			Encoding::Gbk => encoding_rs::GBK.decode_without_bom_handling(bytes).0.into_owned(),
+			Encoding::Raw => bytes.iter().map(|&b| b as char).collect(),
+		}
+	}
+
+	fn encode(self, name: &str) -> Option<Vec<u8>> {
+		match self {
+			Encoding::Sjis => falcom_sjis::encode(name).ok(),
+			Encoding::Gbk => {
+				let (bytes, _, had_errors) = encoding_rs::GBK.encode(name);
+				(!had_errors).then(|| bytes.into_owned())
+			}
+			Encoding::Raw => name.chars().map(|c| u8::try_from(c).ok()).collect(),
+		}
+	}
+
+	fn lowercase(self, name: &str) -> String {
+		match self {
+			Encoding::Sjis | Encoding::Gbk => name.to_lowercase(),
+			// Case conversion outside ascii would not survive a round trip
+			Encoding::Raw => name.to_ascii_lowercase(),
+		}
+	}
+
+	fn uppercase(self, name: &str) -> String {
+		match self {
+			Encoding::Sjis | Encoding::Gbk => name.to_uppercase(),
+			Encoding::Raw => name.to_ascii_uppercase(),
+		}
+	}
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NameError;
@@ -102,6 +269,47 @@ pub struct DirEntry {
 	pub offset: usize,
 }
 
+impl DirEntry {
+	/// The range of the .dat file holding this entry's data.
+	pub fn data_range(&self, dat_len: usize) -> Result<Range<usize>, RangeError> {
+		self.range(self.size, dat_len)
+	}
+
+	/// The range of the .dat file allocated to this entry, including reserved space.
+	pub fn alloc_range(&self, dat_len: usize) -> Result<Range<usize>, RangeError> {
+		self.range(self.size.max(self.reserved_size), dat_len)
+	}
+
+	fn range(&self, size: usize, dat_len: usize) -> Result<Range<usize>, RangeError> {
+		match self.offset.checked_add(size) {
+			Some(end) if end <= dat_len => Ok(self.offset..end),
+			_ => Err(RangeError {
+				name: self.name,
+				offset: self.offset,
+				size,
+				dat_len,
+			}),
+		}
+	}
+}
+
+/// An entry that points outside of the .dat file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeError {
+	pub name: Name,
+	pub offset: usize,
+	pub size: usize,
+	pub dat_len: usize,
+}
+
+impl std::fmt::Display for RangeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{} at {:#X}+{:#X} is outside the dat file ({:#X} bytes)", self.name, self.offset, self.size, self.dat_len)
+	}
+}
+
+impl std::error::Error for RangeError {}
+
 /// Read the list of entries from a .dir file.
 ///
 /// In many cases, .dir files contain a number of trailing entries named `/_______.___`.
@@ -111,7 +319,8 @@ pub fn read_dir(data: &[u8]) -> Result<Vec<DirEntry>, gospel::read::Error> {
 	f.check(b"LB DIR\x1A\0")?;
 	let count = f.u64()? as usize;
 
-	let mut items = Vec::with_capacity(count);
+	// The count is untrusted, so don't let it allocate more than the file could possibly hold.
+	let mut items = Vec::with_capacity(count.min(data.len() / 36));
 
 	for _ in 0..count {
 		items.push(DirEntry {
@@ -128,8 +337,140 @@ pub fn read_dir(data: &[u8]) -> Result<Vec<DirEntry>, gospel::read::Error> {
 	Ok(items)
 }
 
+/// Read the offset table from the start of a .dat file.
+///
+/// This has one more slot than there are entries; in archives created by Falcom, and
+/// by `create` and `rebuild`, each slot's successor marks where that entry's data ends.
+pub fn read_dat(data: &[u8]) -> Result<Vec<usize>, gospel::read::Error> {
+	let mut f = Reader::new(data);
+	f.check(b"LB DAT\x1A\0")?;
+	let count = f.u64()? as usize;
+
+	let mut items = Vec::with_capacity(count.min(data.len() / 4));
+
+	for _ in 0..=count {
+		items.push(f.u32()? as usize);
+	}
+
+	Ok(items)
+}
+
+/// Checks that a .dat file's offset table, as read by [`read_dat`], belongs with the entries of a .dir file.
+///
+/// The number of slots must be the same, and most files must be where the table says they are.
+/// A few files disagreeing is allowed, since that happens in some of Falcom's archives.
+pub fn check_dat(entries: &[DirEntry], table: &[usize], dat_len: usize) -> Result<(), MismatchError> {
+	let files = entries.iter()
+		.enumerate()
+		.filter(|(_, e)| e.name != Name::default() && e.timestamp != 0);
+	let mismatched = files.clone().filter(|(id, e)| table.get(*id) != Some(&e.offset)).count();
+	let files = files.count();
+
+	let problem = if table.len() != entries.len() + 1 {
+		Mismatch::Count { dir: entries.len(), dat: table.len().saturating_sub(1) }
+	} else if mismatched * 2 > files {
+		Mismatch::Offsets { mismatched, files }
+	} else {
+		return Ok(())
+	};
+
+	// Whichever file does not even agree with the size of the dat is the likely culprit
+	let dir_ok = entries.iter().all(|e| e.name == Name::default() || e.alloc_range(dat_len).is_ok());
+	let header = 16 + 4 * table.len();
+	let dat_ok = table.iter().all(|&o| o == 0 || (header..=dat_len).contains(&o));
+	let suspect = match (dir_ok, dat_ok) {
+		(false, true) => Some(Suspect::Dir),
+		(true, false) => Some(Suspect::Dat),
+		_ => None,
+	};
+	Err(MismatchError { problem, suspect })
+}
+
+/// A .dir and .dat file that do not seem to belong together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MismatchError {
+	pub problem: Mismatch,
+	/// Which of the files looks wrong, if it can be told.
+	pub suspect: Option<Suspect>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+	/// The files have different numbers of slots.
+	Count { dir: usize, dat: usize },
+	/// Most files are not where the offset table says they are.
+	Offsets { mismatched: usize, files: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suspect {
+	Dir,
+	Dat,
+}
+
+impl std::fmt::Display for MismatchError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self.problem {
+			Mismatch::Count { dir, dat } => write!(f, "the dir has {dir} slots, but the dat has {dat}")?,
+			Mismatch::Offsets { mismatched, files } => write!(f, "{mismatched} of {files} files are not where the dat says")?,
+		}
+		match self.suspect {
+			Some(Suspect::Dir) => f.write_str("; the dir looks wrong"),
+			Some(Suspect::Dat) => f.write_str("; the dat looks wrong"),
+			None => f.write_str("; they may be from different archives"),
+		}
+	}
+}
+
+impl std::error::Error for MismatchError {}
+
+/// A value that does not fit in the 32-bit fields of the archive format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverflowError {
+	pub name: Name,
+	pub field: &'static str,
+	pub value: u64,
+}
+
+impl std::fmt::Display for OverflowError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{} of {} is {:#X}, which does not fit in 32 bits", self.field, self.name, self.value)
+	}
+}
+
+impl std::error::Error for OverflowError {}
+
+/// Converts a size or offset to the 32 bits used on disk.
+///
+/// `name` and `field` are only used for the error message.
+pub fn to_u32(name: Name, field: &'static str, value: u64) -> Result<u32, OverflowError> {
+	u32::try_from(value).map_err(|_| OverflowError { name, field, value })
+}
+
+/// Writes the offset table for the start of a .dat file, without the header before it.
+///
+/// Each entry that has a name and an offset sets its own slot to its offset, and the following
+/// slot to the end of its allocation, in id order. Slots not set by any entry are zero.
+/// This matches what `create` and `rebuild` produce, as described in [`read_dat`].
+pub fn write_dat_table(entries: &[DirEntry]) -> Result<Vec<u8>, OverflowError> {
+	let mut table = vec![0; entries.len() + 1];
+	for (id, e) in entries.iter().enumerate() {
+		if e.name != Name::default() && e.offset != 0 {
+			let end = e.offset as u64 + e.size.max(e.reserved_size) as u64;
+			table[id] = to_u32(e.name, "offset", e.offset as u64)?;
+			table[id + 1] = to_u32(e.name, "end offset", end)?;
+		}
+	}
+
+	let mut f = Writer::new();
+	for v in table {
+		f.u32(v);
+	}
+	Ok(f.finish().unwrap())
+}
+
 /// Writes a list of entries into a .dir file.
-pub fn write_dir(entries: &[DirEntry]) -> Vec<u8> {
+pub fn write_dir(entries: &[DirEntry]) -> Result<Vec<u8>, OverflowError> {
 	let mut f = Writer::new();
 	f.slice(b"LB DIR\x1A\0");
 	f.u64(entries.len() as u64);
@@ -137,13 +478,13 @@ pub fn write_dir(entries: &[DirEntry]) -> Vec<u8> {
 	for e in entries {
 		f.array::<12>(e.name.0);
 		f.u32(e.unk1);
-		f.u32(e.size as u32);
-		f.u32(e.unk2 as u32);
-		f.u32(e.reserved_size as u32);
+		f.u32(to_u32(e.name, "size", e.size as u64)?);
+		f.u32(to_u32(e.name, "unknown2", e.unk2 as u64)?);
+		f.u32(to_u32(e.name, "reserved size", e.reserved_size as u64)?);
 		f.u32(e.timestamp);
-		f.u32(e.offset as u32);
+		f.u32(to_u32(e.name, "offset", e.offset as u64)?);
 	}
 
-	f.finish().unwrap()
+	Ok(f.finish().unwrap())
 }
 
@@ -0,0 +1,9 @@
+#![feature(try_blocks)]
+
+mod util;
+mod grid;
+mod compression;
+mod packing;
+pub mod dirdat;
+
+pub mod command;
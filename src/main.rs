@@ -1,12 +1,7 @@
-#![feature(try_blocks)]
 use clap::Parser;
 use eyre_span::emit;
 
-mod util;
-mod grid;
-mod dirdat;
-
-mod command;
+use factoria::command;
 
 fn main() -> eyre::Result<()> {
 	use tracing_error::ErrorLayer;
@@ -0,0 +1,94 @@
+/// Options for how data is laid out when writing a new .dat.
+#[derive(Debug, Clone, clap::Args)]
+pub struct Packing {
+	/// Order to store file data in
+	#[clap(long, value_enum, default_value = "id")]
+	pub order: Order,
+
+	/// Start each file's data at a multiple of this many bytes
+	#[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+	pub align: u64,
+
+	/// Extra space to reserve for files matching a glob, as GLOB:BYTES or GLOB:PERCENT%
+	///
+	/// The percentage is of the file's stored size. If several rules match a file, later ones take precedence.
+	/// Files never get less space than they already have reserved.
+	#[clap(long, value_parser = parse_slack)]
+	pub slack: Vec<Slack>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Order {
+	/// By file id
+	Id,
+	/// By file name
+	Name,
+	/// By extension, then by name
+	Extension,
+	/// The order they were stored in before; same as id if there is no previous archive
+	Original,
+}
+
+/// A `--slack` option.
+#[derive(Debug, Clone)]
+pub struct Slack {
+	glob: globset::GlobMatcher,
+	amount: Amount,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Amount {
+	Bytes(usize),
+	Percent(usize),
+}
+
+/// What `Packing::order` needs to know about an entry.
+pub struct SortKey<'a> {
+	pub id: usize,
+	pub name: &'a str,
+	pub offset: Option<usize>,
+}
+
+impl Packing {
+	/// Sorts entries into the order their data should be written in.
+	pub fn order(&self, keys: &mut [SortKey]) {
+		match self.order {
+			Order::Id => keys.sort_by_key(|k| k.id),
+			Order::Name => keys.sort_by(|a, b| (a.name, a.id).cmp(&(b.name, b.id))),
+			Order::Extension => keys.sort_by(|a, b| {
+				let ext = |k: &SortKey| k.name.split_once('.').map_or("", |a| a.1);
+				(ext(a), a.name, a.id).cmp(&(ext(b), b.name, b.id))
+			}),
+			Order::Original => keys.sort_by_key(|k| (k.offset.unwrap_or(usize::MAX), k.id)),
+		}
+	}
+
+	/// How much space should be reserved for a file with the given name and size.
+	pub fn reserve(&self, name: &str, size: usize, reserved_size: usize) -> usize {
+		let slack = self.slack.iter()
+			.rev()
+			.find(|s| s.glob.is_match(name))
+			.map_or(0, |s| match s.amount {
+				Amount::Bytes(n) => n,
+				Amount::Percent(p) => size * p / 100,
+			});
+		reserved_size.max(size + slack)
+	}
+
+	/// Rounds `pos` up to the alignment.
+	pub fn align(&self, pos: u64) -> u64 {
+		pos.next_multiple_of(self.align)
+	}
+}
+
+fn parse_slack(s: &str) -> Result<Slack, String> {
+	let (glob, amount) = s.rsplit_once(':').ok_or("expected GLOB:BYTES or GLOB:PERCENT%")?;
+	let amount = match amount.strip_suffix('%') {
+		Some(p) => Amount::Percent(p.parse().map_err(|_| format!("invalid percentage {amount:?}"))?),
+		None => Amount::Bytes(amount.parse().map_err(|_| format!("invalid size {amount:?}"))?),
+	};
+	Ok(Slack {
+		glob: crate::util::glob(glob).map_err(|e| e.to_string())?.compile_matcher(),
+		amount,
+	})
+}
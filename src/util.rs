@@ -1,6 +1,8 @@
 use camino::{Utf8PathBuf, Utf8Path};
 use clap::builder::TypedValueParser;
 
+use crate::dirdat::{self, DirEntry};
+
 #[tracing::instrument(fields(path=%path))]
 pub fn mmap(path: &Utf8Path) -> eyre::Result<memmap2::Mmap> {
 	let file = std::fs::File::open(path)?;
@@ -13,15 +15,47 @@ pub fn mmap_mut(path: &Utf8Path) -> eyre::Result<memmap2::MmapMut> {
 	Ok(unsafe { memmap2::MmapMut::map_mut(&file)? })
 }
 
+/// Reads the offset table from the start of a .dat file, like [`dirdat::read_dat`] but without reading the rest.
+pub fn read_dat_table(dat: &mut std::fs::File) -> eyre::Result<Vec<usize>> {
+	use std::io::{Read, Seek, SeekFrom};
+	let len = dat.seek(SeekFrom::End(0))?;
+	let mut head = [0; 16];
+	dat.seek(SeekFrom::Start(0))?;
+	dat.read_exact(&mut head).map_err(|_| eyre::eyre!("invalid dat file"))?;
+	eyre::ensure!(head.starts_with(b"LB DAT\x1A\0"), "invalid dat file");
+	let count = u64::from_le_bytes(head[8..].try_into().unwrap());
+	let size = count.saturating_add(1).saturating_mul(4).saturating_add(16).min(len);
+	let mut buf = vec![0; size as usize];
+	dat.seek(SeekFrom::Start(0))?;
+	dat.read_exact(&mut buf)?;
+	Ok(dirdat::read_dat(&buf)?)
+}
+
+/// Checks that the .dat file belongs with the .dir file, as described in [`dirdat::check_dat`].
+///
+/// If `strict` is set, a mismatch is an error, which commands that modify the archive use;
+/// otherwise it is only a warning.
+pub fn check_pair(dir_file: &Utf8Path, dir: &[DirEntry], table: &[usize], dat_len: usize, strict: bool) -> eyre::Result<()> {
+	let Err(e) = dirdat::check_dat(dir, table, dat_len) else { return Ok(()) };
+	let dat_file = dir_file.with_extension("dat");
+	if strict {
+		eyre::bail!("{dat_file} does not match {dir_file}: {e} (use --allow-mismatch to modify it anyway)");
+	}
+	tracing::warn!("{dat_file} does not match {dir_file}: {e}");
+	Ok(())
+}
+
 pub fn glob_parser() -> impl clap::builder::TypedValueParser<Value=globset::Glob> {
-	clap::builder::StringValueParser::new().try_map(|glob| {
-		globset::GlobBuilder::new(&glob)
-			.case_insensitive(true)
-			.backslash_escape(true)
-			.empty_alternates(true)
-			.literal_separator(false)
-			.build()
-	})
+	clap::builder::StringValueParser::new().try_map(|glob| self::glob(&glob))
+}
+
+pub fn glob(glob: &str) -> Result<globset::Glob, globset::Error> {
+	globset::GlobBuilder::new(glob)
+		.case_insensitive(true)
+		.backslash_escape(true)
+		.empty_alternates(true)
+		.literal_separator(false)
+		.build()
 }
 
 pub fn output(output: Option<&Utf8Path>, file: &Utf8Path, extension: &str, n_inputs: usize) -> eyre::Result<Utf8PathBuf> {
@@ -41,3 +75,73 @@ pub fn output(output: Option<&Utf8Path>, file: &Utf8Path, extension: &str, n_inp
 	let name = file.file_name().ok_or_else(|| eyre::eyre!("file has no name"))?;
 	Ok(dir.join(name).with_extension(extension))
 }
+
+/// Maps `iter` in parallel, yielding the results in the original order as they become available.
+pub fn par_map<T, U>(
+	iter: impl Iterator<Item=T> + Send + 'static,
+	map: impl Fn(T) -> U + Send + Sync + 'static,
+) -> impl Iterator<Item=U> where
+	T: Send + 'static,
+	U: Send + 'static,
+{
+	use std::sync::mpsc;
+	use rayon::prelude::*;
+	let (channel_send, channel_recv) = mpsc::channel();
+	std::thread::spawn(move || {
+		iter.map_while(move |item| {
+			let (result_send, result_recv) = mpsc::sync_channel(0);
+			channel_send.send(result_recv).ok()?;
+			Some((item, result_send))
+		})
+		.par_bridge()
+		.try_for_each(|(item, result_send)| {
+			result_send.send(map(item))
+		})
+		.ok()
+	});
+	channel_recv.into_iter().map_while(|a| a.recv().ok())
+}
+
+/// An advisory lock on an archive, released when dropped.
+pub struct Lock {
+	_file: Option<std::fs::File>,
+}
+
+/// Locks the archive with the given .dir file, shared for reading it or exclusive for modifying it.
+///
+/// The lock is taken on a .lock file next to the .dir, rather than on the .dir or .dat themselves, since
+/// those are sometimes replaced, and on Windows, locks also block writes through other handles.
+/// If another process holds a conflicting lock, this fails unless `wait` is set.
+/// Only exclusive locks create the lock file, so that reading an archive leaves nothing behind; shared locks
+/// are skipped if it does not exist or cannot be opened, for example on read-only media.
+pub fn lock(dir_file: &Utf8Path, exclusive: bool, wait: bool) -> eyre::Result<Lock> {
+	use std::fs::TryLockError;
+	let path = dir_file.with_extension("lock");
+	let file = match std::fs::File::options().read(true).write(exclusive).create(exclusive).truncate(false).open(&path) {
+		Ok(file) => file,
+		Err(e) if !exclusive => {
+			tracing::debug!("not locking {path}: {e}");
+			return Ok(Lock { _file: None })
+		}
+		Err(e) => eyre::bail!("cannot create {path}: {e}"),
+	};
+
+	let result = if exclusive { file.try_lock() } else { file.try_lock_shared() };
+	let result = match result {
+		Err(TryLockError::WouldBlock) if wait => {
+			tracing::info!("waiting for another process to finish with {dir_file}");
+			if exclusive { file.lock() } else { file.lock_shared() }
+		}
+		Err(TryLockError::WouldBlock) => eyre::bail!("{dir_file} is in use by another process; use --wait to wait for it"),
+		Err(TryLockError::Error(e)) => Err(e),
+		Ok(()) => Ok(()),
+	};
+	match result {
+		Ok(()) => Ok(Lock { _file: Some(file) }),
+		Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+			tracing::debug!("not locking {path}: {e}");
+			Ok(Lock { _file: None })
+		}
+		Err(e) => Err(e.into()),
+	}
+}
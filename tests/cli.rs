@@ -0,0 +1,1169 @@
+mod support;
+
+use std::time::SystemTime;
+
+use camino::Utf8Path;
+use falcompress::bzip;
+use support::*;
+
+fn mtime(path: &Utf8Path) -> u64 {
+	std::fs::metadata(path).unwrap()
+		.modified().unwrap()
+		.duration_since(SystemTime::UNIX_EPOCH).unwrap()
+		.as_secs()
+}
+
+fn lines(s: &str) -> Vec<&str> {
+	s.lines().map(|a| a.trim_end()).collect()
+}
+
+#[test]
+fn extract_decompresses() {
+	let dir = workdir("extract_decompresses");
+	let archive = Archive::sample();
+	let path = archive.write(&dir, "ED6_DT01");
+
+	run(["extract", path.as_str()]);
+
+	let out = dir.join("ED6_DT01");
+	assert_eq!(tree(&out), ["mode1._dt", "mode2._sn", "plain.txt", "roomy._op"]);
+	for (name, data) in archive.files() {
+		assert_eq!(std::fs::read(out.join(name)).unwrap(), data, "{name}");
+	}
+	for (id, e) in read_dir(&path).iter().enumerate() {
+		if e.timestamp != 0 {
+			let name = archive.slots[id].name().unwrap();
+			assert_eq!(mtime(&out.join(name)), e.timestamp as u64, "{name}");
+		}
+	}
+}
+
+#[test]
+fn extract_raw() {
+	let dir = workdir("extract_raw");
+	let archive = Archive::sample();
+	let path = archive.write(&dir, "ED6_DT01");
+
+	run(["extract", "-C", "-o", dir.join("raw").as_str(), path.as_str()]);
+
+	for slot in &archive.slots {
+		if let Slot::File { name, .. } = slot {
+			assert_eq!(std::fs::read(dir.join("raw").join(name)).unwrap(), slot.stored(), "{name}");
+		}
+	}
+}
+
+#[test]
+fn extract_filters() {
+	let dir = workdir("extract_filters");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+
+	run(["extract", "-a", "-o", dir.join("all").as_str(), path.as_str()]);
+	assert_eq!(tree(&dir.join("all")), ["gone._sn", "mode1._dt", "mode2._sn", "plain.txt", "roomy._op"]);
+	assert_eq!(std::fs::read(dir.join("all/gone._sn")).unwrap(), b"");
+
+	run(["extract", "-g", "*._sn", "-a", "-o", dir.join("sn").as_str(), path.as_str()]);
+	assert_eq!(tree(&dir.join("sn")), ["gone._sn", "mode2._sn"]);
+}
+
+#[test]
+fn list_names() {
+	let dir = workdir("list_names");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+
+	let out = run(["list", "-1", path.as_str()]);
+	assert_eq!(lines(&out), ["plain.txt", "mode1._dt", "mode2._sn", "roomy._op"]);
+
+	let out = run(["list", "-1", "-a", path.as_str()]);
+	assert_eq!(lines(&out), ["plain.txt", "mode1._dt", "gone._sn", "mode2._sn", "roomy._op"]);
+
+	let out = run(["list", "-1", "-A", path.as_str()]);
+	assert_eq!(lines(&out).len(), 7);
+	assert_eq!(lines(&out)[5], "/_______.___");
+
+	let out = run(["list", "-1", "-s", path.as_str()]);
+	assert_eq!(lines(&out), ["mode1._dt", "mode2._sn", "plain.txt", "roomy._op"]);
+}
+
+#[test]
+fn list_long() {
+	let dir = workdir("list_long");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+
+	let out = run(["list", "-l", "-B", path.as_str()]);
+	let lines = lines(&out);
+	assert_eq!(lines.len(), 4);
+	assert!(lines[0].starts_with("00010000 "), "{}", lines[0]);
+	assert!(lines[1].starts_with("00010001 "), "{}", lines[1]);
+	assert!(lines[1].contains("⇒2000 "), "{}", lines[1]);
+	assert!(lines[2].contains("→3000 "), "{}", lines[2]);
+	assert!(lines[3].contains(" 256 "), "{}", lines[3]);
+	assert!(!lines.iter().any(|a| a.contains('•')));
+}
+
+#[test]
+fn add_new_file() {
+	let dir = workdir("add_new_file");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("new.txt"), b"brand new").unwrap();
+
+	run(["add", path.as_str(), dir.join("new.txt").as_str()]);
+
+	let entries = read_dir(&path);
+	assert_eq!(entries[5].name, encode_name("new.txt"));
+	assert_eq!(entries[5].size, 9);
+	assert_eq!(read_dat_table(&path.with_extension("dat"))[5], entries[5].offset);
+	assert_eq!(entries[5].timestamp as u64, mtime(&dir.join("new.txt")));
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/new.txt")).unwrap(), b"brand new");
+}
+
+#[test]
+fn add_compressed() {
+	let dir = workdir("add_compressed");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("new._dt"), text(5000)).unwrap();
+
+	run(["add", "-c=1", path.as_str(), dir.join("new._dt").as_str()]);
+
+	run(["extract", "-C", "-o", dir.join("raw").as_str(), path.as_str()]);
+	let raw = std::fs::read(dir.join("raw/new._dt")).unwrap();
+	let info = bzip::compression_info_ed6(&raw).expect("should be compressed");
+	assert_eq!(info.0, 5000);
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/new._dt")).unwrap(), text(5000));
+}
+
+#[test]
+fn add_replace_keeps_compression() {
+	let dir = workdir("add_replace_keeps_compression");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let before = read_dir(&path);
+	std::fs::write(dir.join("mode2._sn"), text(10000)).unwrap();
+
+	run(["add", path.as_str(), dir.join("mode2._sn").as_str()]);
+
+	let after = read_dir(&path);
+	assert_eq!(after.len(), before.len());
+	assert_eq!(after[3].name, before[3].name);
+	assert!(after[3].offset > before[4].offset, "larger file should be relocated to the end");
+
+	run(["extract", "-C", "-o", dir.join("raw").as_str(), path.as_str()]);
+	let raw = std::fs::read(dir.join("raw/mode2._sn")).unwrap();
+	assert!(bzip::compression_info_ed6(&raw).is_some());
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/mode2._sn")).unwrap(), text(10000));
+}
+
+#[test]
+fn add_within_reserve() {
+	let dir = workdir("add_within_reserve");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let before = read_dir(&path);
+	let dat_size = std::fs::metadata(path.with_extension("dat")).unwrap().len();
+	std::fs::write(dir.join("roomy._op"), [7; 200]).unwrap();
+
+	run(["add", path.as_str(), dir.join("roomy._op").as_str()]);
+
+	let after = read_dir(&path);
+	assert_eq!(after[4].offset, before[4].offset);
+	assert_eq!(after[4].size, 200);
+	assert_eq!(after[4].reserved_size, 256);
+	assert_eq!(std::fs::metadata(path.with_extension("dat")).unwrap().len(), dat_size);
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/roomy._op")).unwrap(), [7; 200]);
+}
+
+#[test]
+fn remove_soft() {
+	let dir = workdir("remove_soft");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let before = read_dir(&path);
+
+	run(["remove", path.as_str(), "plain.txt"]);
+
+	let after = read_dir(&path);
+	assert_eq!(after[0].name, encode_name("plain.txt"));
+	assert_eq!(after[0].timestamp, 0);
+	assert_eq!(after[0].size, 0);
+	let dat = std::fs::read(path.with_extension("dat")).unwrap();
+	let start = before[0].offset as usize;
+	assert!(dat[start..start + before[0].size as usize].iter().all(|a| *a == 0));
+
+	assert_eq!(lines(&run(["list", "-1", path.as_str()])), ["mode1._dt", "mode2._sn", "roomy._op"]);
+	assert!(lines(&run(["list", "-1", "-a", path.as_str()])).contains(&"plain.txt"));
+
+	assert!(log(factoria(["remove", path.as_str(), "plain.txt"])).contains("already soft-deleted"));
+}
+
+#[test]
+fn remove_force() {
+	let dir = workdir("remove_force");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+
+	run(["remove", "-f", path.as_str(), "gone._sn", "mode1._dt"]);
+
+	let after = read_dir(&path);
+	assert_eq!(after[1].name, *b"/_______.___");
+	assert_eq!(after[2].name, *b"/_______.___");
+	assert_eq!(after[1].offset, 0);
+	assert_eq!(read_dat_table(&path.with_extension("dat"))[1], 0);
+	assert_eq!(lines(&run(["list", "-1", "-a", path.as_str()])), ["plain.txt", "mode2._sn", "roomy._op"]);
+}
+
+#[test]
+fn rebuild_prunes() {
+	let dir = workdir("rebuild_prunes");
+	let archive = Archive::sample();
+	let path = archive.write(&dir, "ED6_DT01");
+	let original_size = std::fs::metadata(path.with_extension("dat")).unwrap().len();
+	std::fs::write(dir.join("plain.txt"), text(500)).unwrap();
+
+	run(["add", path.as_str(), dir.join("plain.txt").as_str()]);
+	run(["remove", path.as_str(), "mode1._dt"]);
+	let edited_size = std::fs::metadata(path.with_extension("dat")).unwrap().len();
+	assert!(edited_size > original_size);
+
+	run(["rebuild", "-p", "-n", "8", path.as_str()]);
+
+	let entries = read_dir(&path);
+	assert_eq!(entries.len(), 8);
+	assert_eq!(entries[4].reserved_size, entries[4].size);
+	let table = read_dat_table(&path.with_extension("dat"));
+	assert_eq!(table.len(), 9);
+	let rebuilt_size = std::fs::metadata(path.with_extension("dat")).unwrap().len();
+	assert!(rebuilt_size < edited_size);
+	for (id, e) in entries.iter().enumerate() {
+		if e.offset != 0 {
+			assert_eq!(table[id], e.offset);
+			assert_eq!(table[id + 1], e.offset + e.reserved_size.max(e.size));
+		}
+	}
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(tree(&dir.join("out")), ["mode2._sn", "plain.txt", "roomy._op"]);
+	assert_eq!(std::fs::read(dir.join("out/plain.txt")).unwrap(), text(500));
+	assert_eq!(std::fs::read(dir.join("out/mode2._sn")).unwrap(), text(3000));
+}
+
+#[test]
+fn rebuild_cannot_trim() {
+	let dir = workdir("rebuild_cannot_trim");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let before = std::fs::read(&path).unwrap();
+
+	assert!(log(factoria(["rebuild", "-n", "3", path.as_str()])).contains("cannot trim capacity"));
+	assert_eq!(std::fs::read(&path).unwrap(), before);
+}
+
+#[test]
+fn index_create_roundtrip() {
+	let dir = workdir("index_create_roundtrip");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+
+	run(["extract", path.as_str()]);
+	run(["index", path.as_str()]);
+
+	let json: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("ED6_DT01.json")).unwrap()).unwrap();
+	assert_eq!(json["0x00010000"]["path"], "ED6_DT01/plain.txt");
+	assert_eq!(json["0x00010001"]["compress"], 1);
+	assert_eq!(json["0x00010002"]["path"], serde_json::Value::Null);
+	assert_eq!(json["0x00010002"]["name"], "gone._sn");
+	assert_eq!(json["0x00010003"]["compress"], 2);
+	assert_eq!(json["0x00010004"]["reserve"], 256);
+	assert_eq!(json["0x00010005"], serde_json::Value::Null);
+
+	let out = dir.join("out/ED6_DT01.dir");
+	run(["create", "-o", out.as_str(), dir.join("ED6_DT01.json").as_str()]);
+
+	assert_eq!(std::fs::read(&out).unwrap(), std::fs::read(&path).unwrap());
+	assert_eq!(std::fs::read(out.with_extension("dat")).unwrap(), std::fs::read(path.with_extension("dat")).unwrap());
+}
+
+#[test]
+fn create_from_json() {
+	let dir = workdir("create_from_json");
+	std::fs::create_dir_all(dir.join("files")).unwrap();
+	std::fs::write(dir.join("files/a.txt"), b"aaa").unwrap();
+	std::fs::write(dir.join("files/b._dt"), text(1000)).unwrap();
+	std::fs::write(dir.join("test.json"), r#"{
+		"0x0000": "files/a.txt",
+		"0x0002": { "path": "files/b._dt", "compress": 2, "reserve": 4096 },
+		"0x0003": { "name": "c.txt" },
+		"0x0004": null
+	}"#).unwrap();
+
+	run(["create", dir.join("test.json").as_str()]);
+
+	let path = dir.join("test.dir");
+	let entries = read_dir(&path);
+	assert_eq!(entries.len(), 5);
+	assert_eq!(entries[0].name, encode_name("a.txt"));
+	assert_eq!(entries[1].name, *b"/_______.___");
+	assert_eq!(entries[2].reserved_size, 4096);
+	assert_eq!(entries[3].name, encode_name("c.txt"));
+	assert_eq!(entries[3].timestamp, 0);
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(tree(&dir.join("out")), ["a.txt", "b._dt"]);
+	assert_eq!(std::fs::read(dir.join("out/b._dt")).unwrap(), text(1000));
+}
+
+#[test]
+fn create_rejects_ambiguous_ids() {
+	let dir = workdir("create_rejects_ambiguous_ids");
+	std::fs::write(dir.join("five.json"), r#"{ "0x10000": { "name": "a.txt" } }"#).unwrap();
+	std::fs::write(dir.join("mixed.json"), r#"{ "0x00010000": { "name": "a.txt" }, "0x00020001": { "name": "b.txt" } }"#).unwrap();
+
+	assert!(log(factoria(["create", dir.join("five.json").as_str()])).contains("four hex digits"));
+	assert!(log(factoria(["create", dir.join("mixed.json").as_str()])).contains("different archives"));
+	assert!(!dir.join("five.dir").exists());
+	assert!(!dir.join("mixed.dir").exists());
+}
+
+#[test]
+fn gbk_names() {
+	let dir = workdir("gbk_names");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("地图.txt"), b"map").unwrap();
+
+	assert!(log(factoria(["add", path.as_str(), dir.join("地图.txt").as_str()])).contains("cannot convert to archive name"));
+	run(["add", "--encoding", "gbk", path.as_str(), dir.join("地图.txt").as_str()]);
+
+	assert_eq!(&read_dir(&path)[5].name, b"\xB5\xD8\xCD\xBC    .TXT");
+	assert_eq!(lines(&run(["list", "-1", "--encoding", "gbk", "-g", "*.txt", path.as_str()])), ["plain.txt", "地图.txt"]);
+
+	run(["extract", "--encoding", "gbk", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/地图.txt")).unwrap(), b"map");
+}
+
+#[test]
+fn escaped_names_roundtrip() {
+	let dir = workdir("escaped_names_roundtrip");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	set_name(&path, 0, b"PLAIN\xFF   .TXT");
+	set_name(&path, 1, b"mode1   ._dt");
+	set_name(&path, 3, b"ABCDEFGHIJKL");
+
+	let out = run(["list", "-1", "-a", "-e", path.as_str()]);
+	assert_eq!(lines(&out), [
+		r"PLAIN\xFF\x20\x20\x20.TXT",
+		r"mode1\x20\x20\x20._dt",
+		"gone._sn",
+		r"ABCDEFGHIJK\x4C",
+		"roomy._op",
+	]);
+
+	run(["extract", path.as_str()]);
+	run(["index", path.as_str()]);
+	let json: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("ED6_DT01.json")).unwrap()).unwrap();
+	assert_eq!(json["0x00010000"]["name"], r"PLAIN\xFF\x20\x20\x20.TXT");
+	assert_eq!(json["0x00010004"].get("name"), None);
+
+	let out = dir.join("out/ED6_DT01.dir");
+	run(["create", "-o", out.as_str(), dir.join("ED6_DT01.json").as_str()]);
+	assert_eq!(std::fs::read(&out).unwrap(), std::fs::read(&path).unwrap());
+	assert_eq!(std::fs::read(out.with_extension("dat")).unwrap(), std::fs::read(path.with_extension("dat")).unwrap());
+
+	run(["remove", "-f", path.as_str(), r"mode1\x20\x20\x20._dt"]);
+	assert_eq!(read_dir(&path)[1].name, *b"/_______.___");
+
+	// Only exactly what list -e prints is taken as escaped
+	std::fs::write(dir.join("new.txt"), b"new").unwrap();
+	run(["add", path.as_str(), &format!(r"{}=\x41B.txt", dir.join("new.txt"))]);
+	assert_eq!(read_dir(&path)[1].name, encode_name("x41b.txt"));
+	assert!(log(factoria(["remove", path.as_str(), r"ABCDEFGHIJK\x4c"])).contains("not found"));
+}
+
+#[test]
+fn extract_duplicates() {
+	let dir = workdir("extract_duplicates");
+	let archive = Archive::sample();
+	let path = archive.write(&dir, "ED6_DT01");
+	set_name(&path, 3, b"mode1   ._dt");
+	let mode1 = text(2000);
+	let mode2 = text(3000);
+
+	let out = dir.join("suffix");
+	run(["extract", "-o", out.as_str(), path.as_str()]);
+	assert_eq!(tree(&out), ["mode1._dt", "mode1~0003._dt", "plain.txt", "roomy._op"]);
+	assert_eq!(std::fs::read(out.join("mode1._dt")).unwrap(), mode1);
+	assert_eq!(std::fs::read(out.join("mode1~0003._dt")).unwrap(), mode2);
+
+	let out = dir.join("first");
+	run(["extract", "--duplicates=first", "-o", out.as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(out.join("mode1._dt")).unwrap(), mode1);
+
+	let out = dir.join("last");
+	run(["extract", "--duplicates=last", "-o", out.as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(out.join("mode1._dt")).unwrap(), mode2);
+	assert_eq!(tree(&out).len(), 3);
+
+	let out = dir.join("error");
+	assert!(log(factoria(["extract", "--duplicates=error", "-o", out.as_str(), path.as_str()])).contains("0001, 0003"));
+	assert_eq!(tree(&out), Vec::<String>::new());
+}
+
+#[test]
+fn index_duplicates_and_all() {
+	let dir = workdir("index_duplicates_and_all");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	set_name(&path, 3, b"mode1   ._dt");
+
+	run(["extract", "--duplicates=last", "-a", path.as_str()]);
+	run(["index", "--duplicates=last", "-a", path.as_str()]);
+	let json: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("ED6_DT01.json")).unwrap()).unwrap();
+	assert_eq!(json["0x00010001"]["path"], serde_json::Value::Null);
+	assert_eq!(json["0x00010002"]["path"], "ED6_DT01/gone._sn");
+	assert_eq!(json["0x00010003"]["path"], "ED6_DT01/mode1._dt");
+
+	let out = dir.join("out/ED6_DT01.dir");
+	run(["create", "-o", out.as_str(), dir.join("ED6_DT01.json").as_str()]);
+	run(["extract", "--duplicates=last", "-o", dir.join("again").as_str(), out.as_str()]);
+	assert_eq!(std::fs::read(dir.join("again/mode1._dt")).unwrap(), text(3000));
+}
+
+#[test]
+fn extract_layouts() {
+	let dir = workdir("extract_layouts");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+
+	run(["extract", "--layout=id", "-o", dir.join("id").as_str(), path.as_str()]);
+	assert_eq!(tree(&dir.join("id")), ["0000_plain.txt", "0001_mode1._dt", "0003_mode2._sn", "0004_roomy._op"]);
+
+	run(["extract", "--layout=ext", "-o", dir.join("ext").as_str(), path.as_str()]);
+	assert_eq!(tree(&dir.join("ext")), ["_dt/mode1._dt", "_op/roomy._op", "_sn/mode2._sn", "txt/plain.txt"]);
+
+	run(["extract", "--layout=merged", "-o", dir.join("merged").as_str(), path.as_str()]);
+	assert_eq!(tree(&dir.join("merged")), ["ED6_DT01/mode1._dt", "ED6_DT01/mode2._sn", "ED6_DT01/plain.txt", "ED6_DT01/roomy._op"]);
+}
+
+#[test]
+fn layouts_roundtrip() {
+	for layout in ["--layout=id", "--layout=ext"] {
+		let dir = workdir(&format!("layouts_roundtrip_{}", &layout[9..]));
+		let path = Archive::sample().write(&dir, "ED6_DT01");
+		set_name(&path, 3, b"MODE1   ._DT");
+
+		run(["extract", layout, path.as_str()]);
+		run(["index", layout, path.as_str()]);
+		let out = dir.join("out/ED6_DT01.dir");
+		run(["create", layout, "-o", out.as_str(), dir.join("ED6_DT01.json").as_str()]);
+
+		assert_eq!(std::fs::read(&out).unwrap(), std::fs::read(&path).unwrap(), "{layout}");
+		assert_eq!(std::fs::read(out.with_extension("dat")).unwrap(), std::fs::read(path.with_extension("dat")).unwrap(), "{layout}");
+
+		if layout == "--layout=id" {
+			// 0003_mode1~0003._dt goes back to its own id under its own name
+			let out = dir.join("from_dir/ED6_DT01.dir");
+			run(["create", "--from-dir", dir.join("ED6_DT01").as_str(), layout, "-o", out.as_str()]);
+			let names = read_dir(&out).iter().map(|e| e.name).collect::<Vec<_>>();
+			assert_eq!(names, [
+				encode_name("plain.txt"),
+				encode_name("mode1._dt"),
+				*b"/_______.___",
+				encode_name("mode1._dt"),
+				encode_name("roomy._op"),
+			]);
+		}
+	}
+}
+
+#[test]
+fn extract_update() {
+	let dir = workdir("extract_update");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let out = dir.join("ED6_DT01");
+
+	run(["extract", "-u", path.as_str()]);
+
+	// Same size and mtime, so only noticed when comparing contents
+	let plain = out.join("plain.txt");
+	let time = std::fs::metadata(&plain).unwrap().modified().unwrap();
+	std::fs::write(&plain, b"UNCOMPRESSED DATA\n").unwrap();
+	std::fs::File::options().write(true).open(&plain).unwrap().set_modified(time).unwrap();
+	std::fs::write(out.join("stale.txt"), b"stale").unwrap();
+	run(["remove", path.as_str(), "mode1._dt"]);
+
+	run(["extract", "-u", "-g", "*.txt", path.as_str()]);
+	assert_eq!(std::fs::read(&plain).unwrap(), b"UNCOMPRESSED DATA\n");
+	assert!(out.join("stale.txt").exists());
+
+	run(["extract", "-u", path.as_str()]);
+	assert_eq!(std::fs::read(&plain).unwrap(), b"UNCOMPRESSED DATA\n");
+	assert!(out.join("mode1._dt").exists());
+
+	run(["extract", "-u", "--delete", path.as_str()]);
+	assert_eq!(std::fs::read(&plain).unwrap(), b"UNCOMPRESSED DATA\n");
+	assert_eq!(tree(&out), [".ED6_DT01.manifest", "mode2._sn", "plain.txt", "roomy._op", "stale.txt"]);
+
+	run(["extract", "--update=content", path.as_str()]);
+	assert_eq!(std::fs::read(&plain).unwrap(), b"uncompressed data\n");
+}
+
+#[test]
+fn extract_update_into_existing_dir() {
+	let dir = workdir("extract_update_into_existing_dir");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("notes.txt"), b"mine").unwrap();
+
+	run(["extract", "-u", "--delete", "-o", dir.as_str(), path.as_str()]);
+	run(["remove", path.as_str(), "plain.txt"]);
+	run(["extract", "-u", "--delete", "-o", dir.as_str(), path.as_str()]);
+
+	assert!(!dir.join("plain.txt").exists());
+	assert!(dir.join("mode2._sn").exists());
+	assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"mine");
+	assert!(dir.join("ED6_DT01.dir").exists());
+	assert!(dir.join("ED6_DT01.dat").exists());
+}
+
+#[test]
+fn extract_update_then_create() {
+	let dir = workdir("extract_update_then_create");
+	let sample = Archive::sample();
+	let path = sample.write(&dir, "ED6_DT01");
+	let files = dir.join("files");
+
+	run(["extract", "-u", "-o", files.as_str(), path.as_str()]);
+	assert!(files.join(".ED6_DT01.manifest").exists());
+	let out = dir.join("out/ED6_DT01.dir");
+	run(["create", "--from-dir", files.as_str(), "-o", out.as_str()]);
+
+	run(["extract", out.as_str()]);
+	let mut expected = sample.files().map(|(name, _)| name).collect::<Vec<_>>();
+	expected.sort();
+	assert_eq!(tree(&dir.join("out/ED6_DT01")), expected);
+}
+
+#[test]
+fn extract_to_containers() {
+	let dir = workdir("extract_to_containers");
+	let sample = Archive::sample();
+	let path = sample.write(&dir, "ED6_DT01");
+	let other = Archive::new().file("other.txt", b"other").write(&dir, "ED6_DT02");
+
+	let timestamps = read_dir(&path).iter().enumerate()
+		.filter_map(|(id, e)| Some((sample.slots[id].name()?, e.timestamp)))
+		.collect::<std::collections::HashMap<_, _>>();
+
+	run(["extract", "--to", dir.join("out.tar").as_str(), path.as_str()]);
+	let mut tar = tar::Archive::new(std::fs::File::open(dir.join("out.tar")).unwrap());
+	let mut names = Vec::new();
+	for (i, entry) in tar.entries().unwrap().enumerate() {
+		let mut entry = entry.unwrap();
+		let (name, data) = sample.files().nth(i).unwrap();
+		assert_eq!(entry.path().unwrap().to_str().unwrap(), name);
+		let mut buf = Vec::new();
+		std::io::Read::read_to_end(&mut entry, &mut buf).unwrap();
+		assert_eq!(buf, data);
+		assert_eq!(entry.header().mtime().unwrap(), timestamps[name] as u64, "{name}");
+		names.push(name);
+	}
+	assert_eq!(names, ["plain.txt", "mode1._dt", "mode2._sn", "roomy._op"]);
+
+	run(["extract", "-C", "-g", "mode*", "--to", dir.join("out.zip").as_str(), path.as_str(), other.as_str()]);
+	let mut zip = zip::ZipArchive::new(std::fs::File::open(dir.join("out.zip")).unwrap()).unwrap();
+	let mut names = zip.file_names().map(String::from).collect::<Vec<_>>();
+	names.sort();
+	assert_eq!(names, ["ED6_DT01/mode1._dt", "ED6_DT01/mode2._sn"]);
+	for name in ["mode1._dt", "mode2._sn"] {
+		let t = zip.by_name(&format!("ED6_DT01/{name}")).unwrap().last_modified();
+		let expected = chrono::NaiveDateTime::from_timestamp_opt(timestamps[name] as i64, 0).unwrap();
+		let actual = chrono::NaiveDate::from_ymd_opt(t.year() as i32, t.month() as u32, t.day() as u32).unwrap()
+			.and_hms_opt(t.hour() as u32, t.minute() as u32, t.second() as u32).unwrap();
+		// Zip only stores even seconds
+		assert_eq!(actual.timestamp(), expected.timestamp() & !1, "{name}");
+	}
+	let mut buf = Vec::new();
+	std::io::Read::read_to_end(&mut zip.by_name("ED6_DT01/mode1._dt").unwrap(), &mut buf).unwrap();
+	assert_eq!(buf, Slot::File {
+		name: "mode1._dt".into(),
+		data: text(2000),
+		mode: Some(bzip::CompressMode::Mode1),
+		reserve: None,
+	}.stored());
+}
+
+#[test]
+fn create_from_files() {
+	let dir = workdir("create_from_files");
+	let sample = Archive::sample();
+	let path = sample.write(&dir, "ED6_DT01");
+
+	run(["extract", "--layout", "id", "-o", dir.join("files").as_str(), path.as_str()]);
+	let out = dir.join("from_dir.dir");
+	run([
+		"create", "--from-dir", dir.join("files").as_str(), "-o", out.as_str(), "--layout", "id",
+		"--rule", "*._dt:compress=1", "--rule", "*._op:reserve=256", "--rule", "roomy*:compress=none",
+	]);
+	let entries = read_dir(&out);
+	let names = entries.iter().map(|e| e.name).collect::<Vec<_>>();
+	assert_eq!(names, [
+		encode_name("plain.txt"),
+		encode_name("mode1._dt"),
+		*b"/_______.___",
+		encode_name("mode2._sn"),
+		encode_name("roomy._op"),
+	]);
+	let mode1 = bzip::compress_ed6_to_vec(&text(2000), bzip::CompressMode::Mode1);
+	assert_eq!(entries[1].size as usize, mode1.len());
+	assert_eq!(entries[3].size, 3000);
+	assert_eq!(entries[4].reserved_size, 256);
+	assert_eq!(entries[0].timestamp, BASE_TIMESTAMP);
+	assert_eq!(entries[3].timestamp, BASE_TIMESTAMP + 3);
+
+	let out = dir.join("small_reserve.dir");
+	run(["create", "--from-dir", dir.join("files").as_str(), "-o", out.as_str(), "--layout", "id", "--rule", "*:reserve=100"]);
+	let entries = read_dir(&out);
+	assert_eq!(entries.iter().map(|e| e.reserved_size).collect::<Vec<_>>(), [100, 2000, 0, 3000, 100]);
+	assert_eq!(read_dat_table(&out.with_extension("dat")), expected_dat_table(&entries));
+
+	let out = factoria(["create", "--rule", "*:reserve=100", dir.join("index.json").as_str()]);
+	assert!(!out.status.success());
+
+	run(["extract", "--to", dir.join("files.tar").as_str(), path.as_str()]);
+	run(["extract", "--to", dir.join("files.zip").as_str(), path.as_str()]);
+	for source in ["--from-tar", "--from-zip"] {
+		let ext = &source[7..];
+		let out = dir.join(format!("from_{ext}/ED6_DT01.dir"));
+		run(["create", source, dir.join(format!("files.{ext}")).as_str(), "-o", out.as_str()]);
+		run(["extract", out.as_str()]);
+		let mut files = sample.files().collect::<Vec<_>>();
+		files.sort();
+		for (name, data) in files {
+			assert_eq!(std::fs::read(dir.join(format!("from_{ext}/ED6_DT01")).join(name)).unwrap(), data);
+		}
+	}
+
+	std::fs::write(dir.join("files/0000_PLAIN.TXT"), b"clash").unwrap();
+	let out = factoria(["create", "--from-dir", dir.join("files").as_str(), "--layout", "id", "-o", dir.join("clash.dir").as_str()]);
+	assert!(log(out).contains("would both be named"));
+}
+
+#[test]
+fn recompress() {
+	let dir = workdir("recompress");
+	let sample = Archive::sample();
+	let path = sample.write(&dir, "ED6_DT01");
+	let before = read_dir(&path);
+
+	run(["recompress", "-m", "none", "-g", "mode*", path.as_str()]);
+	run(["recompress", "-m", "1", "-g", "roomy._op", path.as_str()]);
+
+	let after = read_dir(&path);
+	assert_eq!(after[1].size, 2000);
+	assert_eq!(after[3].size, 3000);
+	assert!(after[1].offset > before[4].offset, "larger file should be relocated to the end");
+	assert_eq!(after[4].offset, before[4].offset, "smaller file should stay in place");
+	assert_eq!(after[4].reserved_size, 256);
+	assert_eq!(after[1].timestamp, before[1].timestamp);
+	let table = read_dat_table(&path.with_extension("dat"));
+	assert_eq!(table[1], after[1].offset);
+
+	run(["extract", "-C", "-o", dir.join("raw").as_str(), path.as_str()]);
+	let raw = std::fs::read(dir.join("raw/roomy._op")).unwrap();
+	assert!(bzip::compression_info_ed6(&raw).is_some());
+	assert!(bzip::compression_info_ed6(&std::fs::read(dir.join("raw/mode1._dt")).unwrap()).is_none());
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	for (name, data) in sample.files() {
+		assert_eq!(std::fs::read(dir.join("out").join(name)).unwrap(), data);
+	}
+}
+
+#[test]
+fn auto_compression() {
+	let dir = workdir("auto_compression");
+	let path = Archive::sample().placeholder().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("text._dt"), text(5000)).unwrap();
+	std::fs::write(dir.join("tiny._dt"), b"xy").unwrap();
+	std::fs::write(dir.join("strict._dt"), text(5000)).unwrap();
+
+	run(["add", "-c=auto", path.as_str(), dir.join("text._dt").as_str(), dir.join("tiny._dt").as_str()]);
+	run(["add", "-c=auto", "--min-savings=100", path.as_str(), dir.join("strict._dt").as_str()]);
+
+	let entries = read_dir(&path);
+	let mode1 = bzip::compress_ed6_to_vec(&text(5000), bzip::CompressMode::Mode1).len();
+	let mode2 = bzip::compress_ed6_to_vec(&text(5000), bzip::CompressMode::Mode2).len();
+	assert_eq!(entries[5].size as usize, mode1.min(mode2));
+	assert_eq!(entries[6].size, 2, "compression should not be used if it does not help");
+	assert_eq!(entries[7].name, encode_name("strict._dt"));
+	assert_eq!(entries[7].size, 5000);
+
+	run(["extract", path.as_str()]);
+	run(["index", "--auto", path.as_str()]);
+	let json: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("ED6_DT01.json")).unwrap()).unwrap();
+	assert_eq!(json["0x00010000"]["compress"], "auto");
+	assert_eq!(json["0x00010006"]["compress"], "auto");
+
+	let out = dir.join("out/ED6_DT01.dir");
+	run(["create", "-o", out.as_str(), dir.join("ED6_DT01.json").as_str()]);
+	let created = read_dir(&out);
+	assert_eq!(created[5].size, entries[5].size);
+	assert_eq!(created[0].size, 18);
+}
+
+#[test]
+fn verify_compression() {
+	let dir = workdir("verify_compression");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("new._dt"), text(5000)).unwrap();
+
+	run(["add", "-c=1", "--verify", path.as_str(), dir.join("new._dt").as_str()]);
+	run(["recompress", "-m", "2", "--verify", "-g", "*._dt", path.as_str()]);
+
+	run(["extract", path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("ED6_DT01/new._dt")).unwrap(), text(5000));
+}
+
+#[test]
+fn stats() {
+	let dir = workdir("stats");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	run(["remove", path.as_str(), "plain.txt"]);
+
+	let json: serde_json::Value = serde_json::from_str(&run(["stats", "--json", path.as_str()])).unwrap();
+	let stats = &json[path.as_str()];
+	assert_eq!(stats["capacity"], 7);
+	assert_eq!(stats["files"], 3);
+	assert_eq!(stats["placeholders"], 2);
+	assert_eq!(stats["deleted"], 2);
+	assert_eq!(stats["decompressed_size"], 2000 + 3000 + 27);
+	assert_eq!(stats["padding_size"], 256 - 27);
+	assert_eq!(stats["gap_size"], 18);
+	assert_eq!(stats["extensions"]["_dt"]["compressed"], 1);
+	assert_eq!(stats["extensions"]["_sn"]["files"], 1);
+	let stored = read_dir(&path).iter().filter(|e| e.timestamp != 0).map(|e| e.size as u64).sum::<u64>();
+	assert_eq!(stats["stored_size"], stored);
+
+	let out = run(["stats", "-B", path.as_str()]);
+	assert!(out.contains("3 of 7 slots, 2 placeholders, 2 deleted"), "{out}");
+}
+
+#[test]
+fn map() {
+	let dir = workdir("map");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	run(["remove", path.as_str(), "plain.txt"]);
+
+	let svg = dir.join("map.svg");
+	let out = run(["map", "-w", "40", "-l", "2", "-B", "--svg", svg.as_str(), path.as_str()]);
+	let lines = out.lines().collect::<Vec<_>>();
+	assert_eq!(lines[0].chars().count(), 40);
+	assert_eq!(lines[1].chars().count(), 40);
+	assert!(lines[0].starts_with('▓'));
+	assert!(lines[2].contains("gaps 18"), "{out}");
+	assert!(lines[2].contains(&format!("padding {}", 256 - 27)), "{out}");
+	assert!(lines[3].contains("reclaimable: 18 with rebuild"), "{out}");
+
+	let svg = std::fs::read_to_string(svg).unwrap();
+	assert!(svg.starts_with("<svg"));
+	assert!(svg.contains("0004 roomy._op (padding)"));
+}
+
+#[test]
+fn defrag() {
+	let dir = workdir("defrag");
+	let sample = Archive::sample();
+	let path = sample.write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("mode1._dt"), text(20000)).unwrap();
+	run(["remove", path.as_str(), "plain.txt"]);
+	run(["add", path.as_str(), dir.join("mode1._dt").as_str()]);
+
+	run(["defrag", path.as_str()]);
+
+	let entries = read_dir(&path);
+	let table = read_dat_table(&path.with_extension("dat"));
+	let mut pos = 16 + 4 * (entries.len() as u32 + 1);
+	let mut by_offset = entries.iter().enumerate().filter(|(_, e)| e.offset != 0).collect::<Vec<_>>();
+	by_offset.sort_by_key(|(_, e)| e.offset);
+	for (id, e) in by_offset {
+		assert_eq!(e.offset, pos, "{id:04X} should be packed");
+		assert_eq!(table[id], e.offset);
+		pos += e.size.max(e.reserved_size);
+	}
+	assert_eq!(std::fs::metadata(path.with_extension("dat")).unwrap().len(), pos as u64);
+	assert!(!dir.join("ED6_DT01.dir.tmp").exists());
+
+	run(["extract", path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("ED6_DT01/mode1._dt")).unwrap(), text(20000));
+	for (name, data) in sample.files().filter(|(n, _)| !["plain.txt", "mode1._dt"].contains(n)) {
+		assert_eq!(std::fs::read(dir.join("ED6_DT01").join(name)).unwrap(), data);
+	}
+}
+
+#[test]
+fn defrag_repairs_offset_table() {
+	let dir = workdir("defrag_repairs_offset_table");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("new.txt"), b"new").unwrap();
+
+	// As if a defrag was interrupted after moving files, but before updating the table
+	let mut dat = std::fs::read(path.with_extension("dat")).unwrap();
+	let count = read_dir(&path).len();
+	dat[16..16 + 4 * (count + 1)].fill(0);
+	std::fs::write(path.with_extension("dat"), dat).unwrap();
+	assert!(log(factoria(["add", path.as_str(), dir.join("new.txt").as_str()])).contains("does not match"));
+
+	run(["defrag", path.as_str()]);
+	assert_eq!(read_dat_table(&path.with_extension("dat")), expected_dat_table(&read_dir(&path)));
+	run(["add", path.as_str(), dir.join("new.txt").as_str()]);
+}
+
+#[test]
+fn defrag_reuses_scratch_space() {
+	let dir = workdir("defrag_reuses_scratch_space");
+	let path = Archive::new()
+		.file("gap.txt", &text(100))
+		.file("a.txt", &text(1000))
+		.file("b.txt", &text(1500))
+		.file("c.txt", &text(1200))
+		.write(&dir, "ED6_DT01");
+	run(["remove", path.as_str(), "gap.txt"]);
+	let dat_len = std::fs::metadata(path.with_extension("dat")).unwrap().len();
+
+	// Every file overlaps its own old location, so each one is staged past the end of the dat
+	let out = std::process::Command::new(env!("CARGO_BIN_EXE_factoria"))
+		.args(["defrag", path.as_str()])
+		.env("RUST_LOG", "factoria::command::defrag=debug")
+		.env("NO_COLOR", "1")
+		.output()
+		.unwrap();
+	assert!(out.status.success());
+	let log = log(out);
+	let staged = log.lines().filter_map(|l| l.split("moving via ").nth(1)).collect::<Vec<_>>();
+	assert_eq!(staged, [format!("{dat_len:#X}"); 3]);
+	assert_eq!(std::fs::metadata(path.with_extension("dat")).unwrap().len(), dat_len - 100);
+
+	run(["extract", path.as_str()]);
+	for (name, len) in [("a.txt", 1000), ("b.txt", 1500), ("c.txt", 1200)] {
+		assert_eq!(std::fs::read(dir.join("ED6_DT01").join(name)).unwrap(), text(len), "{name}");
+	}
+}
+
+#[test]
+fn rebuild_order_and_align() {
+	let dir = workdir("rebuild_order_and_align");
+	let sample = Archive::sample();
+	let path = sample.write(&dir, "ED6_DT01");
+
+	let out = dir.join("out/ED6_DT01.dir");
+	run([
+		"rebuild", "-o", out.as_str(), path.as_str(),
+		"--order", "extension", "--align", "64", "--slack", "*._sn:50%", "--slack", "plain.txt:100",
+	]);
+
+	let entries = read_dir(&out);
+	let mut by_offset = (0..entries.len()).filter(|&i| entries[i].offset != 0).collect::<Vec<_>>();
+	by_offset.sort_by_key(|&i| entries[i].offset);
+	// _dt, _op, _sn (gone before mode2), txt
+	assert_eq!(by_offset, [1, 4, 2, 3, 0]);
+	for &i in &by_offset {
+		assert_eq!(entries[i].offset % 64, 0);
+	}
+	let mode2 = &entries[3];
+	assert_eq!(mode2.reserved_size, mode2.size + mode2.size / 2);
+	assert_eq!(entries[0].reserved_size, 18 + 100);
+	assert_eq!(entries[2].reserved_size, 0, "deleted files get no slack");
+	assert_eq!(entries[4].reserved_size, 256);
+
+	let table = read_dat_table(&out.with_extension("dat"));
+	for &i in &by_offset {
+		assert_eq!(table[i], entries[i].offset);
+	}
+
+	run(["extract", out.as_str()]);
+	for (name, data) in sample.files() {
+		assert_eq!(std::fs::read(dir.join("out/ED6_DT01").join(name)).unwrap(), data);
+	}
+
+	run(["index", out.as_str()]);
+	let recreated = dir.join("recreated/ED6_DT01.dir");
+	run(["create", "-o", recreated.as_str(), "--order", "name", "--align", "16", dir.join("out/ED6_DT01.json").as_str()]);
+	let entries = read_dir(&recreated);
+	let mut by_offset = (0..entries.len()).filter(|&i| entries[i].offset != 0).collect::<Vec<_>>();
+	by_offset.sort_by_key(|&i| entries[i].offset);
+	// gone, mode1, mode2, plain, roomy
+	assert_eq!(by_offset, [2, 1, 3, 0, 4]);
+	assert!(by_offset.iter().all(|&i| entries[i].offset % 16 == 0));
+}
+
+#[test]
+fn set_metadata() {
+	let dir = workdir("set_metadata");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let before = read_dir(&path);
+
+	run(["set", path.as_str(), "*._sn", "--timestamp", "2020-01-02 03:04:05", "--unknown1", "7"]);
+	let after = read_dir(&path);
+	assert_eq!(after[3].timestamp, 1_577_934_245);
+	assert_eq!(after[3].unk1, 7);
+	assert_eq!(after[2], before[2], "soft-deleted files are not matched by globs");
+	assert_eq!(after[1], before[1]);
+
+	run(["set", path.as_str(), "roomy._op", "--reserve", "100"]);
+	let after = read_dir(&path);
+	assert_eq!(after[4].offset, before[4].offset);
+	assert_eq!(after[4].reserved_size, 100);
+	let dat_size = std::fs::metadata(path.with_extension("dat")).unwrap().len();
+	assert_eq!(dat_size, before[4].offset as u64 + 100, "freed space at the end is truncated");
+
+	run(["set", path.as_str(), "plain.txt", "--reserve", "4096"]);
+	let after = read_dir(&path);
+	assert_eq!(after[0].offset as u64, dat_size);
+	assert_eq!(after[0].reserved_size, 4096);
+	assert_eq!(after[0].size, before[0].size);
+	assert_eq!(read_dat_table(&path.with_extension("dat"))[0], after[0].offset);
+
+	// Leftovers in the space given up are cleared, so that it can still be truncated
+	let mut dat = std::fs::read(path.with_extension("dat")).unwrap();
+	let len = dat.len();
+	dat[len - 10..].fill(0xFF);
+	std::fs::write(path.with_extension("dat"), dat).unwrap();
+	let size = before[0].size.to_string();
+	run(["set", path.as_str(), "plain.txt", "--reserve", size.as_str()]);
+	let dat_size = std::fs::metadata(path.with_extension("dat")).unwrap().len();
+	assert_eq!(dat_size, (after[0].offset + before[0].size) as u64);
+
+	assert!(log(factoria(["set", path.as_str(), "plain.txt", "--reserve", "4"])).contains("cannot reserve"));
+	assert!(log(factoria(["set", path.as_str(), "gone._sn", "-t", "now"])).contains("soft-deleted"));
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	for (name, data) in Archive::sample().files() {
+		assert_eq!(std::fs::read(dir.join("out").join(name)).unwrap(), data);
+	}
+}
+
+#[test]
+fn add_renamed() {
+	let dir = workdir("add_renamed");
+	let path = Archive::sample().placeholder().placeholder().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("long file name.txt"), b"renamed").unwrap();
+	std::fs::write(dir.join("other.bin"), b"mapped").unwrap();
+
+	run(["add", path.as_str(), dir.join("long file name.txt").as_str(), "--as", "short.txt"]);
+	run(["add", path.as_str(), &format!("{}=plain.txt", dir.join("other.bin"))]);
+	let out = factoria_stdin(["add", path.as_str(), "-=piped.dat"], b"from stdin");
+	assert!(out.status.success(), "{}", log(out));
+
+	let entries = read_dir(&path);
+	assert_eq!(entries[5].name, encode_name("short.txt"));
+	assert_eq!(entries[6].name, encode_name("piped.dat"));
+	assert_eq!(entries[7].name, *b"/_______.___");
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/short.txt")).unwrap(), b"renamed");
+	assert_eq!(std::fs::read(dir.join("out/plain.txt")).unwrap(), b"mapped");
+	assert_eq!(std::fs::read(dir.join("out/piped.dat")).unwrap(), b"from stdin");
+
+	// An existing file is never split, and only an `=` in the file name gives a new name
+	std::fs::write(dir.join("k=v.txt"), b"equals").unwrap();
+	std::fs::create_dir(dir.join("a=b")).unwrap();
+	std::fs::write(dir.join("a=b/data.bin"), b"nested").unwrap();
+	run(["add", path.as_str(), dir.join("k=v.txt").as_str()]);
+	run(["add", path.as_str(), &format!("{}=mapped.bin", dir.join("a=b/data.bin"))]);
+	let entries = read_dir(&path);
+	assert_eq!(entries[7].name, encode_name("k=v.txt"));
+	assert_eq!(entries[8].name, encode_name("mapped.bin"));
+	run(["extract", "-o", dir.join("out2").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out2/k=v.txt")).unwrap(), b"equals");
+	assert_eq!(std::fs::read(dir.join("out2/mapped.bin")).unwrap(), b"nested");
+
+	assert!(log(factoria(["add", path.as_str(), "-"])).contains("a name is needed"));
+	assert!(log(factoria(["add", path.as_str(), "a", "b", "--as", "c"])).contains("single file"));
+}
+
+#[test]
+fn add_at_id() {
+	let dir = workdir("add_at_id");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("new.txt"), b"new").unwrap();
+
+	run(["add", path.as_str(), dir.join("new.txt").as_str(), "--id", "0x6"]);
+	let entries = read_dir(&path);
+	assert_eq!(entries[5].name, *b"/_______.___");
+	assert_eq!(entries[6].name, encode_name("new.txt"));
+
+	let out = log(factoria(["add", path.as_str(), dir.join("new.txt").as_str(), "--id", "5"]));
+	assert!(out.contains("already in archive at 0006"), "{out}");
+	let out = log(factoria(["add", path.as_str(), &format!("{}=other.txt", dir.join("new.txt")), "--id", "0"]));
+	assert!(out.contains("occupied by plain.txt"), "{out}");
+	assert!(log(factoria(["add", path.as_str(), dir.join("new.txt").as_str(), "--id", "7"])).contains("only 7 slots"));
+
+	// A directory passes the stat, but cannot be read, which leaves the slot as it was
+	std::fs::create_dir(dir.join("unreadable.txt")).unwrap();
+	let before = read_dir(&path);
+	let out = factoria(["add", path.as_str(), dir.join("unreadable.txt").as_str(), "--id", "2"]);
+	assert!(log(out).contains("ERROR"));
+	let out = factoria(["add", path.as_str(), dir.join("unreadable.txt").as_str()]);
+	assert!(log(out).contains("ERROR"));
+	assert_eq!(read_dir(&path), before);
+
+	let out = factoria(["add", path.as_str(), &format!("{}=other.txt", dir.join("new.txt")), "--id", "2"]);
+	assert!(out.status.success());
+	assert!(log(out).contains("reusing slot of deleted file gone._sn"));
+	assert_eq!(read_dir(&path)[2].name, encode_name("other.txt"));
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/other.txt")).unwrap(), b"new");
+}
+
+#[test]
+fn add_many() {
+	let dir = workdir("add_many");
+	let mut archive = Archive::sample();
+	for _ in 0..8 {
+		archive = archive.placeholder();
+	}
+	let path = archive.write(&dir, "ED6_DT01");
+
+	let mut args = vec!["add".to_owned(), "-c=auto".to_owned(), path.to_string()];
+	for i in 0..10 {
+		let file = dir.join(format!("file{i}.txt"));
+		std::fs::write(&file, text(1000 + i * 100)).unwrap();
+		args.push(file.to_string());
+	}
+	args.push(format!("{}=file3.txt", dir.join("file0.txt")));
+	let out = log(factoria(&args));
+	assert!(out.contains("file3.txt is given more than once"), "{out}");
+
+	let entries = read_dir(&path);
+	for i in 0..10 {
+		assert_eq!(entries[5 + i].name, encode_name(&format!("file{i}.txt")));
+	}
+	let mut offsets = entries[5..].iter().map(|e| e.offset).collect::<Vec<_>>();
+	assert!(offsets.is_sorted(), "files are written in the order given");
+	offsets.dedup();
+	assert_eq!(offsets.len(), 10);
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	for i in 0..10 {
+		assert_eq!(std::fs::read(dir.join(format!("out/file{i}.txt"))).unwrap(), text(1000 + i * 100));
+	}
+}
+
+#[test]
+fn trailing_space_truncated() {
+	let dir = workdir("trailing_space_truncated");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let before = read_dir(&path);
+	let dat_path = path.with_extension("dat");
+
+	run(["remove", path.as_str(), "roomy._op"]);
+	assert_eq!(std::fs::metadata(&dat_path).unwrap().len(), before[4].offset as u64);
+	let table = read_dat_table(&dat_path);
+	assert_eq!(table[5], before[4].offset);
+
+	run(["remove", path.as_str(), "mode2._sn"]);
+	let len = std::fs::metadata(&dat_path).unwrap().len();
+	assert_eq!(len, before[3].offset as u64);
+	let after = read_dir(&path);
+	assert_eq!(after[4].offset as u64, len, "deleted files are moved to the new end");
+	assert_eq!(read_dat_table(&dat_path)[4] as u64, len);
+
+	std::fs::write(dir.join("plain.txt"), text(100)).unwrap();
+	run(["add", path.as_str(), dir.join("plain.txt").as_str()]);
+	std::fs::write(dir.join("plain.txt"), text(50)).unwrap();
+	run(["add", "-r", "0", path.as_str(), dir.join("plain.txt").as_str()]);
+	let after = read_dir(&path);
+	assert_eq!(std::fs::metadata(&dat_path).unwrap().len(), after[0].offset as u64 + 100);
+	assert_eq!(read_dat_table(&dat_path), expected_dat_table(&after));
+
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert_eq!(std::fs::read(dir.join("out/plain.txt")).unwrap(), text(50));
+	assert_eq!(std::fs::read(dir.join("out/mode1._dt")).unwrap(), text(2000));
+}
+
+#[test]
+fn dat_table_maintained() {
+	let dir = workdir("dat_table_maintained");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let dat_path = path.with_extension("dat");
+	assert_eq!(read_dat_table(&dat_path), expected_dat_table(&read_dir(&path)));
+
+	std::fs::write(dir.join("roomy._op"), text(1000)).unwrap();
+	std::fs::write(dir.join("new.txt"), b"new").unwrap();
+	run(["add", path.as_str(), dir.join("roomy._op").as_str(), dir.join("new.txt").as_str()]);
+	assert_eq!(read_dat_table(&dat_path), expected_dat_table(&read_dir(&path)));
+
+	run(["remove", "-f", path.as_str(), "new.txt", "mode1._dt"]);
+	assert_eq!(read_dat_table(&dat_path), expected_dat_table(&read_dir(&path)));
+	assert!(!run(["list", "-l", path.as_str()]).contains('◦'));
+
+	let mut dat = std::fs::read(&dat_path).unwrap();
+	dat[16 + 4 * 5..][..4].fill(0);
+	std::fs::write(&dat_path, dat).unwrap();
+	let long = run(["list", "-l", path.as_str()]);
+	let roomy = long.lines().find(|l| l.contains("roomy._op")).unwrap();
+	assert!(roomy.contains('◦'), "{long}");
+}
+
+#[test]
+fn mismatched_pairs() {
+	let dir = workdir("mismatched_pairs");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	let small = Archive::new().file("a.txt", b"a").file("b.txt", b"b").write(&dir, "SMALL");
+	let mut bigger = Archive::sample();
+	bigger.slots[0] = Archive::new().file("plain.txt", &text(40)).slots.remove(0);
+	let bigger = bigger.write(&dir, "BIGGER");
+
+	std::fs::copy(small.with_extension("dat"), path.with_extension("dat")).unwrap();
+	let dir_before = std::fs::read(&path).unwrap();
+	std::fs::write(dir.join("new.txt"), b"new").unwrap();
+	let out = log(factoria(["add", path.as_str(), dir.join("new.txt").as_str()]));
+	assert!(out.contains("the dir has 7 slots, but the dat has 2"), "{out}");
+	assert!(out.contains("--allow-mismatch"), "{out}");
+	assert_eq!(std::fs::read(&path).unwrap(), dir_before);
+
+	let out = factoria(["list", path.as_str()]);
+	assert!(out.status.success());
+	assert!(log(out).contains("ED6_DT01.dat does not match"));
+
+	std::fs::copy(Archive::sample().write(&dir, "SAMPLE").with_extension("dat"), bigger.with_extension("dat")).unwrap();
+	let out = log(factoria(["remove", bigger.as_str(), "plain.txt"]));
+	assert!(out.contains("3 of 4 files are not where the dat says; the dir looks wrong"), "{out}");
+	assert!(read_dir(&bigger)[0].timestamp != 0);
+
+	let out = log(factoria(["remove", "--allow-mismatch", bigger.as_str(), "plain.txt"]));
+	assert!(out.contains("BIGGER.dat does not match") && !out.contains("use --allow-mismatch"), "{out}");
+	assert_eq!(read_dir(&bigger)[0].timestamp, 0);
+}
+
+#[test]
+fn archive_locking() {
+	let dir = workdir("archive_locking");
+	let path = Archive::sample().write(&dir, "ED6_DT01");
+	std::fs::write(dir.join("new.txt"), b"new").unwrap();
+
+	run(["list", path.as_str()]);
+	run(["extract", "-o", dir.join("out").as_str(), path.as_str()]);
+	assert!(!path.with_extension("lock").exists(), "reading should not leave a lock file");
+
+	let lock = std::fs::File::create(path.with_extension("lock")).unwrap();
+	lock.lock_shared().unwrap();
+	run(["list", path.as_str()]);
+	let out = log(factoria(["add", path.as_str(), dir.join("new.txt").as_str()]));
+	assert!(out.contains("in use by another process; use --wait"), "{out}");
+	assert_eq!(read_dir(&path)[5].name, *b"/_______.___");
+
+	lock.unlock().unwrap();
+	lock.lock().unwrap();
+	assert!(log(factoria(["list", path.as_str()])).contains("in use by another process"));
+
+	let release = std::thread::spawn(move || {
+		std::thread::sleep(std::time::Duration::from_millis(500));
+		drop(lock);
+	});
+	run(["add", "--wait", path.as_str(), dir.join("new.txt").as_str()]);
+	release.join().unwrap();
+	assert_eq!(read_dir(&path)[5].name, encode_name("new.txt"));
+
+	// Spelled differently, but still in place, so it must not wait for itself
+	run(["rebuild", "-o", &format!("{dir}/"), path.as_str()]);
+	assert_eq!(read_dir(&path)[5].name, encode_name("new.txt"));
+}
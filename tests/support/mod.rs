@@ -0,0 +1,346 @@
+//! Synthetic `LB DIR`/`LB DAT` archives for testing.
+//!
+//! This deliberately does not share any code with the archive handling in factoria itself,
+//! so that a bug in one is not silently mirrored in the other.
+#![allow(dead_code)]
+
+use std::process::Output;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use falcompress::bzip;
+
+/// Timestamp of the first entry in a fixture; each following entry is one second later.
+pub const BASE_TIMESTAMP: u32 = 1_262_304_000;
+
+/// A single slot in a fixture archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Slot {
+	/// An unused slot, named `/_______.___`.
+	Placeholder,
+	/// A file name without any data, as left by `remove` or found in many of Falcom's archives.
+	Deleted { name: String },
+	/// A file with data.
+	File {
+		name: String,
+		data: Vec<u8>,
+		mode: Option<bzip::CompressMode>,
+		reserve: Option<usize>,
+	},
+}
+
+impl Slot {
+	pub fn name(&self) -> Option<&str> {
+		match self {
+			Slot::Placeholder => None,
+			Slot::Deleted { name } | Slot::File { name, .. } => Some(name),
+		}
+	}
+
+	/// The bytes as stored in the dat, excluding any reserved padding.
+	pub fn stored(&self) -> Vec<u8> {
+		match self {
+			Slot::File { data, mode: Some(mode), .. } => bzip::compress_ed6_to_vec(data, *mode),
+			Slot::File { data, mode: None, .. } => data.clone(),
+			_ => Vec::new(),
+		}
+	}
+}
+
+/// Builder for a synthetic archive.
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+	pub slots: Vec<Slot>,
+}
+
+impl Archive {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The standard fixture, which has at least one of each kind of slot.
+	pub fn sample() -> Self {
+		Archive::new()
+			.file("plain.txt", b"uncompressed data\n")
+			.compressed("mode1._dt", &text(2000), bzip::CompressMode::Mode1)
+			.deleted("gone._sn")
+			.compressed("mode2._sn", &text(3000), bzip::CompressMode::Mode2)
+			.reserved("roomy._op", b"some data with room to grow", 256)
+			.placeholder()
+			.placeholder()
+	}
+
+	pub fn file(mut self, name: &str, data: &[u8]) -> Self {
+		self.slots.push(Slot::File { name: name.into(), data: data.to_vec(), mode: None, reserve: None });
+		self
+	}
+
+	pub fn compressed(mut self, name: &str, data: &[u8], mode: bzip::CompressMode) -> Self {
+		self.slots.push(Slot::File { name: name.into(), data: data.to_vec(), mode: Some(mode), reserve: None });
+		self
+	}
+
+	pub fn reserved(mut self, name: &str, data: &[u8], reserve: usize) -> Self {
+		assert!(reserve >= data.len());
+		self.slots.push(Slot::File { name: name.into(), data: data.to_vec(), mode: None, reserve: Some(reserve) });
+		self
+	}
+
+	pub fn deleted(mut self, name: &str) -> Self {
+		self.slots.push(Slot::Deleted { name: name.into() });
+		self
+	}
+
+	pub fn placeholder(mut self) -> Self {
+		self.slots.push(Slot::Placeholder);
+		self
+	}
+
+	/// Uncompressed contents of all files that have data, in id order.
+	pub fn files(&self) -> impl Iterator<Item=(&str, &[u8])> {
+		self.slots.iter().filter_map(|s| match s {
+			Slot::File { name, data, .. } => Some((name.as_str(), data.as_slice())),
+			_ => None,
+		})
+	}
+
+	/// Serializes the archive, returning the contents of the .dir and .dat files.
+	///
+	/// Data is laid out back to back in id order, like `factoria rebuild` does.
+	pub fn build(&self) -> (Vec<u8>, Vec<u8>) {
+		let count = self.slots.len();
+		let mut dir = Vec::new();
+		dir.extend_from_slice(b"LB DIR\x1A\0");
+		dir.extend_from_slice(&(count as u64).to_le_bytes());
+
+		let mut table = vec![0u32; count + 1];
+		let mut data = Vec::new();
+		let header = 16 + 4 * (count + 1);
+
+		for (id, slot) in self.slots.iter().enumerate() {
+			let (name, size, reserved_size, timestamp, offset) = match slot {
+				Slot::Placeholder => (*b"/_______.___", 0, 0, 0, 0),
+				_ => {
+					let stored = slot.stored();
+					let reserve = match slot {
+						Slot::File { reserve: Some(r), .. } => *r,
+						_ => stored.len(),
+					};
+					let pos = header + data.len();
+					data.extend_from_slice(&stored);
+					data.resize(data.len() + reserve.saturating_sub(stored.len()), 0);
+					table[id] = pos as u32;
+					table[id + 1] = (header + data.len()) as u32;
+					let timestamp = match slot {
+						Slot::File { .. } => BASE_TIMESTAMP + id as u32,
+						_ => 0,
+					};
+					(encode_name(slot.name().unwrap()), stored.len(), reserve, timestamp, pos)
+				}
+			};
+			dir.extend_from_slice(&name);
+			dir.extend_from_slice(&0u32.to_le_bytes());
+			dir.extend_from_slice(&(size as u32).to_le_bytes());
+			dir.extend_from_slice(&0u32.to_le_bytes());
+			dir.extend_from_slice(&(reserved_size as u32).to_le_bytes());
+			dir.extend_from_slice(&timestamp.to_le_bytes());
+			dir.extend_from_slice(&(offset as u32).to_le_bytes());
+		}
+
+		let mut dat = Vec::new();
+		dat.extend_from_slice(b"LB DAT\x1A\0");
+		dat.extend_from_slice(&(count as u64).to_le_bytes());
+		for v in table {
+			dat.extend_from_slice(&v.to_le_bytes());
+		}
+		dat.extend_from_slice(&data);
+
+		(dir, dat)
+	}
+
+	/// Writes `<stem>.dir` and `<stem>.dat` into `dir`, returning the path to the .dir file.
+	pub fn write(&self, dir: &Utf8Path, stem: &str) -> Utf8PathBuf {
+		let (dir_data, dat_data) = self.build();
+		let path = dir.join(format!("{stem}.dir"));
+		std::fs::write(&path, dir_data).unwrap();
+		std::fs::write(path.with_extension("dat"), dat_data).unwrap();
+		path
+	}
+}
+
+/// Encodes an ascii name into the 8.3 form used in .dir files.
+pub fn encode_name(name: &str) -> [u8; 12] {
+	let name = name.to_uppercase();
+	let (name, ext) = name.split_once('.').unwrap_or((&name, ""));
+	assert!(name.is_ascii() && name.len() <= 8 && ext.len() <= 3);
+	let mut o = *b"        .   ";
+	o[..name.len()].copy_from_slice(name.as_bytes());
+	o[9..][..ext.len()].copy_from_slice(ext.as_bytes());
+	o
+}
+
+/// Somewhat compressible text of the given length.
+pub fn text(len: usize) -> Vec<u8> {
+	b"The district is made up of 64 blocks, with blocks one through eight designed as the Ark's primary trade port. "
+		.iter()
+		.cycle()
+		.take(len)
+		.copied()
+		.collect()
+}
+
+/// A parsed .dir entry, for inspecting the results of commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+	pub name: [u8; 12],
+	pub unk1: u32,
+	pub size: u32,
+	pub unk2: u32,
+	pub reserved_size: u32,
+	pub timestamp: u32,
+	pub offset: u32,
+}
+
+pub fn read_dir(path: &Utf8Path) -> Vec<DirEntry> {
+	let data = std::fs::read(path).unwrap();
+	assert_eq!(&data[..8], b"LB DIR\x1A\0");
+	let count = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+	assert_eq!(data.len(), 16 + 36 * count);
+	let u32_at = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+	(0..count).map(|i| {
+		let p = 16 + 36 * i;
+		DirEntry {
+			name: data[p..p + 12].try_into().unwrap(),
+			unk1: u32_at(p + 12),
+			size: u32_at(p + 16),
+			unk2: u32_at(p + 20),
+			reserved_size: u32_at(p + 24),
+			timestamp: u32_at(p + 28),
+			offset: u32_at(p + 32),
+		}
+	}).collect()
+}
+
+/// Overwrites the name of an entry in a .dir file.
+pub fn set_name(path: &Utf8Path, id: usize, name: &[u8; 12]) {
+	let mut data = std::fs::read(path).unwrap();
+	data[16 + 36 * id..][..12].copy_from_slice(name);
+	std::fs::write(path, data).unwrap();
+}
+
+/// Reads the offset table at the start of a .dat file.
+pub fn read_dat_table(path: &Utf8Path) -> Vec<u32> {
+	let data = std::fs::read(path).unwrap();
+	assert_eq!(&data[..8], b"LB DAT\x1A\0");
+	let count = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+	(0..=count)
+		.map(|i| u32::from_le_bytes(data[16 + 4 * i..][..4].try_into().unwrap()))
+		.collect()
+}
+
+/// The offset table that `create` and `rebuild` would write for these entries.
+pub fn expected_dat_table(entries: &[DirEntry]) -> Vec<u32> {
+	let mut table = vec![0; entries.len() + 1];
+	for (id, e) in entries.iter().enumerate() {
+		if e.name != *b"/_______.___" && e.offset != 0 {
+			table[id] = e.offset;
+			table[id + 1] = e.offset + e.size.max(e.reserved_size);
+		}
+	}
+	table
+}
+
+/// Creates an empty scratch directory unique to the given test.
+pub fn workdir(test: &str) -> Utf8PathBuf {
+	let path = Utf8Path::new(env!("CARGO_TARGET_TMPDIR")).join(test);
+	if path.exists() {
+		std::fs::remove_dir_all(&path).unwrap();
+	}
+	std::fs::create_dir_all(&path).unwrap();
+	path
+}
+
+/// Runs factoria with the given arguments.
+pub fn factoria<I, S>(args: I) -> Output where
+	I: IntoIterator<Item=S>,
+	S: AsRef<std::ffi::OsStr>,
+{
+	std::process::Command::new(env!("CARGO_BIN_EXE_factoria"))
+		.args(args)
+		.env("RUST_LOG", "warn")
+		.env("NO_COLOR", "1")
+		.output()
+		.unwrap()
+}
+
+/// Runs factoria with the given arguments, writing `input` to its stdin.
+pub fn factoria_stdin<I, S>(args: I, input: &[u8]) -> Output where
+	I: IntoIterator<Item=S>,
+	S: AsRef<std::ffi::OsStr>,
+{
+	use std::io::Write;
+	use std::process::Stdio;
+	let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_factoria"))
+		.args(args)
+		.env("RUST_LOG", "warn")
+		.env("NO_COLOR", "1")
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.unwrap();
+	child.stdin.take().unwrap().write_all(input).unwrap();
+	child.wait_with_output().unwrap()
+}
+
+/// Runs factoria, asserting that it succeeds without logging any errors,
+/// and returns its output with terminal escapes removed.
+pub fn run<I, S>(args: I) -> String where
+	I: IntoIterator<Item=S>,
+	S: AsRef<std::ffi::OsStr>,
+{
+	let out = factoria(args);
+	let stdout = strip_ansi(&String::from_utf8_lossy(&out.stdout));
+	let stderr = strip_ansi(&String::from_utf8_lossy(&out.stderr));
+	assert!(out.status.success(), "factoria failed:\n{stdout}\n{stderr}");
+	assert!(!stdout.contains("ERROR") && !stderr.contains("ERROR"), "factoria logged errors:\n{stdout}\n{stderr}");
+	stdout
+}
+
+/// All output from a factoria invocation, with terminal escapes removed.
+pub fn log(out: Output) -> String {
+	let mut s = strip_ansi(&String::from_utf8_lossy(&out.stdout));
+	s.push_str(&strip_ansi(&String::from_utf8_lossy(&out.stderr)));
+	s
+}
+
+pub fn strip_ansi(s: &str) -> String {
+	let mut out = String::new();
+	let mut escape = false;
+	for c in s.chars() {
+		match c {
+			'\x1B' => escape = true,
+			'm' if escape => escape = false,
+			c if !escape => out.push(c),
+			_ => {}
+		}
+	}
+	out
+}
+
+/// Lists the names of all files under `path`, relative to it, in sorted order.
+pub fn tree(path: &Utf8Path) -> Vec<String> {
+	fn walk(base: &Utf8Path, path: &Utf8Path, out: &mut Vec<String>) {
+		for e in path.read_dir_utf8().unwrap() {
+			let e = e.unwrap();
+			if e.file_type().unwrap().is_dir() {
+				walk(base, e.path(), out);
+			} else {
+				out.push(e.path().strip_prefix(base).unwrap().as_str().replace('\\', "/"));
+			}
+		}
+	}
+	let mut out = Vec::new();
+	walk(path, path, &mut out);
+	out.sort();
+	out
+}